@@ -0,0 +1,50 @@
+//! Throughput of discovery's classification hot path, over a long synthetic call
+//!
+//! Run with `cargo bench`. Criterion isn't a dependency, so this times the runs itself and prints packets per second.
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::RwLock;
+use std::time::Instant;
+
+use chrono::{Duration, TimeZone, Utc};
+use stoppable_thread::SimpleAtomicBool;
+
+use zoom_tally::stream_analyser::{CaptureOptions, NextPacket, PacketTime, PortDiscoveryCapture, VecPacketSource};
+use zoom_tally::zoom_channels::ZoomChannels;
+
+/// How many times to run discovery over the packets, taking the fastest
+const RUNS: u32 = 10;
+
+/// How many 20ms rounds of packets to send on each stream
+const ROUNDS: u64 = 50_000;
+
+/// Video, audio and control streams, plus a few other ports, with packet sizes that wander a bit like real traffic
+fn call_packets(start: Instant) -> Vec<NextPacket> {
+    let streams: [(u16, u16); 6] = [(50000, 1000), (50001, 200), (50002, 60), (50003, 900), (50004, 150), (50005, 40)];
+    (0..ROUNDS).flat_map(|round| streams.iter().map(move |(port, length)| {
+        let offset = std::time::Duration::from_millis(round * 20);
+        let time = PacketTime {
+            captured_at: Utc.timestamp_opt(1_600_000_000, 0).unwrap() + Duration::from_std(offset).unwrap(),
+            seen_at: start + offset
+        };
+        let length = length + (round % 7) as u16 * length / 20;
+        NextPacket::Udp(*port, length, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), time)
+    })).collect()
+}
+
+fn main() {
+    let packets = call_packets(Instant::now());
+    let options = CaptureOptions::default();
+
+    let fastest = (0..RUNS).map(|_| {
+        let channel_map = RwLock::new(ZoomChannels::new());
+        let mut source = VecPacketSource::new(packets.clone());
+        let started = Instant::now();
+        PortDiscoveryCapture::discover(&mut source, &options, &channel_map, &SimpleAtomicBool::new(false))
+            .expect("discovery failed");
+        started.elapsed()
+    }).min().unwrap();
+
+    println!("discover: {} packets in {:?} (fastest of {}), {:.0} packets/s", packets.len(), fastest, RUNS,
+        packets.len() as f64 / fastest.as_secs_f64());
+}
@@ -0,0 +1,240 @@
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+use stoppable_thread::StoppableHandle;
+
+use crate::custom_device::CustomDevice;
+use crate::error::ZoomTallyError;
+use crate::sink::StatusSink;
+use crate::stream_analyser::{CaptureOptions, PortDiscoveryCapture, PortRange, SizeBucket};
+use crate::zoom_channels::{ZoomChannels, ZoomSessionState};
+
+/// How long the statuses wait after the last packet before reporting each channel off
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatusTimeouts {
+    /// Time after which the video, audio or screen share is considered off
+    pub av_off_ms: i64,
+    /// Time after which the call, or the control channel, is considered off
+    pub call_timeout_ms: i64,
+    /// How long the call must stay silent past the timeout before it's reported as off
+    pub call_linger_ms: i64
+}
+
+impl Default for StatusTimeouts {
+    fn default() -> Self {
+        let state = ZoomSessionState::new();
+        StatusTimeouts {
+            av_off_ms: state.av_off_ms,
+            call_timeout_ms: state.call_timeout_ms,
+            call_linger_ms: state.call_linger_ms
+        }
+    }
+}
+
+/// A capture device, the options to capture with and the sinks to report to, ready to start
+///
+/// Start the capture thread with `start`, then call `update` periodically to recalculate the statuses and publish
+/// them to each sink.
+pub struct ZoomChannelCapture {
+    pub device: CustomDevice,
+    pub options: CaptureOptions,
+    /// Streams the capture thread has found, shared with it
+    pub channels: Arc<RwLock<ZoomChannels>>,
+    /// Statuses as of the last `update`
+    pub session_state: ZoomSessionState,
+    sinks: Vec<Box<dyn StatusSink>>
+}
+
+impl ZoomChannelCapture {
+    pub fn builder() -> ZoomChannelCaptureBuilder {
+        ZoomChannelCaptureBuilder::new()
+    }
+
+    /// Start discovering the Zoom channels on a new thread, updating `channels` as they're found
+    pub fn start(&self) -> StoppableHandle<Result<(), ZoomTallyError>> {
+        let device = self.device.clone();
+        let options = self.options.clone();
+        let channels = self.channels.clone();
+        stoppable_thread::spawn(move |stopped| PortDiscoveryCapture::run(device, options, channels, stopped))
+    }
+
+    /// Recalculate the statuses from the latest channels and publish them, and anything that changed, to every sink
+    pub fn update(&mut self, now: Instant) {
+        let previous = self.session_state.clone();
+        self.session_state.update_channels(&self.channels.read().unwrap(), now);
+        let events = self.session_state.events_since(&previous);
+        for sink in self.sinks.iter_mut() {
+            sink.publish(&self.session_state, &events);
+        }
+    }
+}
+
+/// Build a `ZoomChannelCapture`, setting only the options that differ from the defaults
+///
+/// ```no_run
+/// use zoom_tally::capture::ZoomChannelCapture;
+/// use zoom_tally::stream_analyser::PortRange;
+///
+/// let mut capture = ZoomChannelCapture::builder()
+///     .ports(vec![PortRange {start: 8801, end: 8810}])
+///     .build()?;
+/// let _thread = capture.start();
+/// loop {
+///     capture.update(std::time::Instant::now());
+///     println!("Call: {}", capture.session_state.call.as_str());
+///     std::thread::sleep(std::time::Duration::from_millis(100));
+/// }
+/// # Ok::<(), zoom_tally::error::ZoomTallyError>(())
+/// ```
+pub struct ZoomChannelCaptureBuilder {
+    device: Option<CustomDevice>,
+    options: CaptureOptions,
+    timeouts: StatusTimeouts,
+    sinks: Vec<Box<dyn StatusSink>>
+}
+
+impl Default for ZoomChannelCaptureBuilder {
+    fn default() -> Self {
+        ZoomChannelCaptureBuilder::new()
+    }
+}
+
+impl ZoomChannelCaptureBuilder {
+    pub fn new() -> ZoomChannelCaptureBuilder {
+        ZoomChannelCaptureBuilder {
+            device: None,
+            options: CaptureOptions::default(),
+            timeouts: StatusTimeouts::default(),
+            sinks: Vec::new()
+        }
+    }
+
+    /// Capture on this device, rather than the system's default
+    pub fn device(mut self, device: CustomDevice) -> Self {
+        self.device = Some(device);
+        self
+    }
+
+    /// Replace all the capture options, e.g. to set ones there isn't a method for. Set this before the other methods,
+    /// as it overwrites their changes.
+    pub fn options(mut self, options: CaptureOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Packet size tiers to classify streams with, largest first
+    pub fn thresholds(mut self, size_buckets: Vec<SizeBucket>) -> Self {
+        self.options.size_buckets = size_buckets;
+        self
+    }
+
+    /// How long each status waits before reporting off
+    pub fn timeouts(mut self, timeouts: StatusTimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Destination ports Zoom traffic is sent to
+    pub fn ports(mut self, zoom_ports: Vec<PortRange>) -> Self {
+        self.options.zoom_ports = zoom_ports;
+        self
+    }
+
+    /// Publish each update to this sink, as well as any added already
+    pub fn sink(mut self, sink: Box<dyn StatusSink>) -> Self {
+        self.sinks.push(sink);
+        self
+    }
+
+    /// Finish building, looking up the default device if none was given
+    pub fn build(self) -> Result<ZoomChannelCapture, ZoomTallyError> {
+        let device = match self.device {
+            Some(device) => device,
+            None => CustomDevice::default_device()?
+        };
+        let mut session_state = ZoomSessionState::new();
+        session_state.av_off_ms = self.timeouts.av_off_ms;
+        session_state.call_timeout_ms = self.timeouts.call_timeout_ms;
+        session_state.call_linger_ms = self.timeouts.call_linger_ms;
+        Ok(ZoomChannelCapture {
+            device,
+            options: self.options,
+            channels: Arc::new(RwLock::new(ZoomChannels::new())),
+            session_state,
+            sinks: self.sinks
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::stream_analyser::ChannelKind;
+    use crate::zoom_channels::{SessionEvent, ZoomChannelStatus};
+
+    fn test_device() -> CustomDevice {
+        CustomDevice::from(pcap::Device {name: "zoom-tally-test0".to_string(), desc: None})
+    }
+
+    /// Call status and events of each update a sink was given
+    type Published = Rc<RefCell<Vec<(ZoomChannelStatus, Vec<SessionEvent>)>>>;
+
+    /// A sink that records every update it's given
+    struct RecordingSink(Published);
+
+    impl StatusSink for RecordingSink {
+        fn publish(&mut self, state: &ZoomSessionState, events: &[SessionEvent]) {
+            self.0.borrow_mut().push((state.call, events.to_vec()));
+        }
+    }
+
+    #[test]
+    fn builder_defaults_match_the_options_and_state_defaults() {
+        let capture = ZoomChannelCapture::builder().device(test_device()).build().unwrap();
+        let options = CaptureOptions::default();
+        assert_eq!(capture.options.zoom_ports, options.zoom_ports);
+        assert_eq!(capture.options.size_buckets, options.size_buckets);
+        let state = ZoomSessionState::new();
+        assert_eq!(capture.session_state.av_off_ms, state.av_off_ms);
+        assert_eq!(capture.session_state.call_timeout_ms, state.call_timeout_ms);
+        assert_eq!(capture.session_state.call_linger_ms, state.call_linger_ms);
+    }
+
+    #[test]
+    fn builder_sets_only_what_it_is_given() {
+        let buckets = vec![SizeBucket {above: 300, kind: ChannelKind::Video}];
+        let capture = ZoomChannelCapture::builder()
+            .device(test_device())
+            .thresholds(buckets.clone())
+            .ports(vec![PortRange {start: 8801, end: 8810}])
+            .timeouts(StatusTimeouts {av_off_ms: 500, ..StatusTimeouts::default()})
+            .build().unwrap();
+
+        assert_eq!(capture.options.size_buckets, buckets);
+        assert_eq!(capture.options.zoom_ports, vec![PortRange {start: 8801, end: 8810}]);
+        assert_eq!(capture.session_state.av_off_ms, 500);
+        assert_eq!(capture.session_state.call_timeout_ms, StatusTimeouts::default().call_timeout_ms);
+        assert!(capture.options.control_channel);
+    }
+
+    #[test]
+    fn update_publishes_to_every_sink() {
+        let first: Published = Rc::default();
+        let second: Published = Rc::default();
+        let mut capture = ZoomChannelCapture::builder()
+            .device(test_device())
+            .sink(Box::new(RecordingSink(first.clone())))
+            .sink(Box::new(RecordingSink(second.clone())))
+            .build().unwrap();
+
+        capture.update(Instant::now());
+        capture.update(Instant::now());
+
+        assert_eq!(first.borrow().len(), 2);
+        assert_eq!(*first.borrow(), *second.borrow());
+        assert_eq!(first.borrow()[1].0, capture.session_state.call);
+    }
+}
@@ -1,31 +1,201 @@
+use std::net::IpAddr;
+#[cfg(unix)]
+use std::net::{Ipv4Addr, Ipv6Addr};
+#[cfg(unix)]
+use std::ffi::CStr;
+
 use pcap::Device;
 
+use crate::error::ZoomTallyError;
+
 /// Tweaked implementation of pcap::Device that's cloneable, and has some helper methods
 #[derive(Debug, Clone)]
 pub struct CustomDevice {
     name: String,
-    desc: Option<String>
+    desc: Option<String>,
+    addresses: Vec<IpAddr>,
+    /// Hardware address, formatted like `00:11:22:33:44:55`, if the OS tells us
+    mac_address: Option<String>
 }
 
 impl From<Device> for CustomDevice {
     fn from(dev: Device) -> Self {
-        CustomDevice {name: dev.name.clone(), desc: dev.desc.clone()}
+        CustomDevice::with_addresses(dev.name, dev.desc)
     }
 }
 
+/// Look up the IP addresses and hardware address assigned to a network interface
+///
+/// pcap 0.7 doesn't expose these on `Device`, so ask the OS directly. Returns no addresses if the interface has none
+/// or can't be queried. The hardware address is only looked up on Linux.
+#[cfg(unix)]
+fn interface_addresses(name: &str) -> (Vec<IpAddr>, Option<String>) {
+    let mut addresses = Vec::new();
+    // Only the Linux AF_PACKET arm below fills this in
+    #[cfg_attr(not(target_os = "linux"), allow(unused_mut))]
+    let mut mac_address = None;
+    let mut ifaddrs: *mut libc::ifaddrs = std::ptr::null_mut();
+
+    unsafe {
+        if libc::getifaddrs(&mut ifaddrs) != 0 {
+            return (addresses, mac_address);
+        }
+
+        let mut current = ifaddrs;
+        while !current.is_null() {
+            let ifaddr = &*current;
+            if !ifaddr.ifa_addr.is_null() && CStr::from_ptr(ifaddr.ifa_name).to_str() == Ok(name) {
+                match i32::from((*ifaddr.ifa_addr).sa_family) {
+                    libc::AF_INET => {
+                        let sockaddr = &*(ifaddr.ifa_addr as *const libc::sockaddr_in);
+                        addresses.push(IpAddr::V4(Ipv4Addr::from(u32::from_be(sockaddr.sin_addr.s_addr))));
+                    },
+                    libc::AF_INET6 => {
+                        let sockaddr = &*(ifaddr.ifa_addr as *const libc::sockaddr_in6);
+                        addresses.push(IpAddr::V6(Ipv6Addr::from(sockaddr.sin6_addr.s6_addr)));
+                    },
+                    #[cfg(target_os = "linux")]
+                    libc::AF_PACKET => {
+                        let sockaddr = &*(ifaddr.ifa_addr as *const libc::sockaddr_ll);
+                        let length = usize::from(sockaddr.sll_halen).min(sockaddr.sll_addr.len());
+                        if length > 0 {
+                            mac_address = Some(format_mac_address(&sockaddr.sll_addr[..length]));
+                        }
+                    },
+                    _ => ()
+                }
+            }
+            current = ifaddr.ifa_next;
+        }
+
+        libc::freeifaddrs(ifaddrs);
+    }
+
+    (addresses, mac_address)
+}
+
+/// Format a hardware address as colon separated hex bytes
+#[cfg(target_os = "linux")]
+fn format_mac_address(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<String>>().join(":")
+}
+
+/// List the devices pcap can capture from
+fn list_devices() -> Result<Vec<Device>, ZoomTallyError> {
+    Device::list().map_err(|e| ZoomTallyError::Device(format!("Couldn't list network devices: {}", e)))
+}
+
+#[cfg(not(unix))]
+fn interface_addresses(_name: &str) -> (Vec<IpAddr>, Option<String>) {
+    (Vec::new(), None)
+}
+
+/// Name of the interface the default route goes out of, i.e. the one carrying internet traffic
+///
+/// Read from the kernel's routing tables, preferring the IPv4 default route with the lowest metric, then IPv6.
+#[cfg(target_os = "linux")]
+fn default_route_interface() -> Option<String> {
+    // Columns are: Iface Destination Gateway Flags RefCnt Use Metric Mask MTU Window IRTT, with the addresses in hex
+    let ipv4_route = std::fs::read_to_string("/proc/net/route").ok().and_then(|table| {
+        table.lines().skip(1)
+            .filter_map(|line| {
+                let columns: Vec<&str> = line.split_whitespace().collect();
+                let is_up = u32::from_str_radix(columns.get(3)?, 16).ok()? & 0x1 != 0;
+                if is_up && *columns.get(1)? == "00000000" && *columns.get(7)? == "00000000" {
+                    Some((columns.get(6)?.parse::<u32>().ok()?, columns[0].to_string()))
+                } else {
+                    None
+                }
+            })
+            .min()
+            .map(|(_, name)| name)
+    });
+
+    // Columns are: destination prefix_length source source_prefix_length next_hop metric refcount use flags iface
+    ipv4_route.or_else(|| std::fs::read_to_string("/proc/net/ipv6_route").ok().and_then(|table| {
+        table.lines()
+            .filter_map(|line| {
+                let columns: Vec<&str> = line.split_whitespace().collect();
+                let is_default = columns.first()?.chars().all(|c| c == '0') && *columns.get(1)? == "00";
+                // The kernel's catch-all unreachable route goes via loopback
+                if is_default && *columns.get(9)? != "lo" {
+                    Some((u32::from_str_radix(columns.get(5)?, 16).ok()?, columns[9].to_string()))
+                } else {
+                    None
+                }
+            })
+            .min()
+            .map(|(_, name)| name)
+    }))
+}
+
+/// Name of the interface the default route goes out of, i.e. the one carrying internet traffic
+#[cfg(target_os = "macos")]
+fn default_route_interface() -> Option<String> {
+    let output = std::process::Command::new("route").args(["-n", "get", "default"]).output().ok()?;
+    String::from_utf8_lossy(&output.stdout).lines()
+        .find_map(|line| line.trim().strip_prefix("interface:").map(|name| name.trim().to_string()))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn default_route_interface() -> Option<String> {
+    None
+}
+
 impl CustomDevice {
-/// Construct a device given the name (i.e. look up description), or panic if it doesn't exist
-pub fn device_from_name(name: String) -> CustomDevice {
-    let device_list = Device::list().unwrap();
+/// Pick a device to capture from when none is given: the one the default route goes out of, or pcap's choice if
+/// that can't be worked out
+///
+/// pcap's choice is often `any` or the wrong interface on machines with more than one.
+pub fn default_device() -> Result<CustomDevice, ZoomTallyError> {
+    if let Some(device) = default_route_interface().and_then(|name| CustomDevice::device_from_name(name).ok()) {
+        return Ok(device);
+    }
+    Ok(CustomDevice::from(Device::lookup()?))
+}
+
+/// Construct a device given the name (i.e. look up description), or an error if it doesn't exist
+pub fn device_from_name(name: String) -> Result<CustomDevice, ZoomTallyError> {
+    let device_list = list_devices()?;
     let target_device_instance = device_list.iter().find(|dev| dev.name == name);
 
     match target_device_instance {
-        Some(dev) => CustomDevice {name: dev.name.clone(), desc: dev.desc.clone()},
-        None => panic!("No known device called {:?}", name)
+        Some(dev) => Ok(CustomDevice::with_addresses(dev.name.clone(), dev.desc.clone())),
+        None => {
+            let known_names: Vec<&str> = device_list.iter().map(|dev| dev.name.as_str()).collect();
+            Err(ZoomTallyError::Device(format!("No known device called {:?}, available devices are: {}", name,
+                known_names.join(", "))))
+        }
     }
 }
 
-pub fn to_pcap_device(self) -> Device {
+/// Construct a device given its position in the `--list` output (numbered from 0)
+pub fn from_index(index: usize) -> Result<CustomDevice, ZoomTallyError> {
+    let device_list = list_devices()?;
+    let device_count = device_list.len();
+
+    device_list.into_iter().nth(index)
+        .map(CustomDevice::from)
+        .ok_or(ZoomTallyError::Device(format!("No device with index {}, there are {} devices", index, device_count)))
+}
+
+pub fn to_pcap_device(&self) -> Device {
     Device {name: self.name.clone(), desc: self.desc.clone()}
 }
+
+/// IP addresses assigned to this device
+pub fn addresses(&self) -> &[IpAddr] {
+    &self.addresses
+}
+
+/// Hardware address of this device, if known
+pub fn mac_address(&self) -> Option<&str> {
+    self.mac_address.as_deref()
+}
+
+/// Construct a device, looking up its addresses from the OS
+fn with_addresses(name: String, desc: Option<String>) -> CustomDevice {
+    let (addresses, mac_address) = interface_addresses(&name);
+    CustomDevice {name, desc, addresses, mac_address}
+}
 }
\ No newline at end of file
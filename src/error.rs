@@ -0,0 +1,50 @@
+use std::fmt;
+
+/// Errors that stop zoom-tally from capturing
+#[derive(Debug)]
+pub enum ZoomTallyError {
+    /// A network device couldn't be found or listed
+    Device(String),
+    /// pcap couldn't open, filter or read from a capture
+    Capture(pcap::Error)
+}
+
+impl fmt::Display for ZoomTallyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ZoomTallyError::Device(message) => write!(f, "{}", message),
+            ZoomTallyError::Capture(e) if is_permission_error(e) => {
+                write!(f, "Capture failed: {} - try running with sudo, or on Linux give the binary capture \
+                           rights with `sudo setcap cap_net_raw,cap_net_admin=eip`", e)
+            },
+            ZoomTallyError::Capture(e) => write!(f, "Capture failed: {}", e)
+        }
+    }
+}
+
+/// Whether pcap failed because we aren't allowed to capture, rather than anything being wrong with the device
+fn is_permission_error(e: &pcap::Error) -> bool {
+    match e {
+        pcap::Error::PcapError(message) => {
+            let message = message.to_lowercase();
+            message.contains("permission") || message.contains("not permitted")
+        },
+        pcap::Error::IoError(kind) => *kind == std::io::ErrorKind::PermissionDenied,
+        _ => false
+    }
+}
+
+impl std::error::Error for ZoomTallyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ZoomTallyError::Device(_) => None,
+            ZoomTallyError::Capture(e) => Some(e)
+        }
+    }
+}
+
+impl From<pcap::Error> for ZoomTallyError {
+    fn from(e: pcap::Error) -> Self {
+        ZoomTallyError::Capture(e)
+    }
+}
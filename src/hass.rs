@@ -0,0 +1,78 @@
+#![cfg(feature = "hass")]
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::Duration;
+
+/// Entity the status is posted to
+const HASS_ENTITY_ID: &str = "sensor.zoom_status";
+
+/// How long to wait on Home Assistant before giving up on an update
+const HASS_TIMEOUT_MSEC: u64 = 2000;
+
+/// Posts the call status to Home Assistant's REST API as the state of a sensor entity
+///
+/// Updates are sent from a background thread so a slow or unreachable server doesn't hold up status updates. Only
+/// plain `http://` URLs are supported.
+pub struct HassClient {
+    updates: Sender<String>
+}
+
+impl HassClient {
+    /// Start a client for the Home Assistant instance at `url`, e.g. `http://homeassistant.local:8123`
+    ///
+    /// # Arguments
+    /// * `url` - Base URL of Home Assistant
+    /// * `token` - Long-lived access token to authenticate with
+    pub fn new(url: &str, token: &str) -> Result<HassClient, String> {
+        let host = url.strip_prefix("http://")
+            .ok_or(format!("Home Assistant URL {:?} should start with http://", url))?
+            .trim_end_matches('/')
+            .to_string();
+        if host.is_empty() || host.contains('/') {
+            return Err(format!("Home Assistant URL {:?} should just be the server, like http://homeassistant.local:8123", url));
+        }
+        let address = if host.contains(':') { host.clone() } else { format!("{}:80", host) };
+        let token = token.to_string();
+
+        let (updates, received_updates) = mpsc::channel::<String>();
+        thread::spawn(move || {
+            for body in received_updates {
+                if let Err(e) = post_state(&address, &host, &token, &body) {
+                    eprintln!("Couldn't update Home Assistant: {}", e);
+                }
+            }
+        });
+
+        Ok(HassClient { updates })
+    }
+
+    /// Queue an update of the entity, given its state and (already JSON formatted) attributes object
+    pub fn update(&self, state: &str, attributes: &str) {
+        let _ = self.updates.send(format!("{{\"state\":\"{}\",\"attributes\":{}}}", state, attributes));
+    }
+}
+
+/// Send a single state update, and check Home Assistant accepted it
+fn post_state(address: &str, host: &str, token: &str, body: &str) -> std::io::Result<()> {
+    let mut stream = TcpStream::connect(address)?;
+    stream.set_read_timeout(Some(Duration::from_millis(HASS_TIMEOUT_MSEC)))?;
+    stream.set_write_timeout(Some(Duration::from_millis(HASS_TIMEOUT_MSEC)))?;
+
+    write!(stream, "POST /api/states/{} HTTP/1.1\r\nHost: {}\r\nAuthorization: Bearer {}\r\n\
+        Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        HASS_ENTITY_ID, host, token, body.len(), body)?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+
+    // Home Assistant answers 200 for an existing entity, or 201 when it's created
+    let status_line = response.lines().next().unwrap_or_default();
+    if status_line.contains(" 200 ") || status_line.contains(" 201 ") {
+        Ok(())
+    } else {
+        Err(std::io::Error::other(format!("unexpected response {:?}", status_line)))
+    }
+}
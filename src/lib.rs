@@ -0,0 +1,43 @@
+//! Detect the state of audio and video on active Zoom calls
+//!
+//! The analysis behind the `zoom-tally` binary, for embedding in other tools. Run
+//! `stream_analyser::PortDiscoveryCapture` (then `PortMonitorCapture` once the channels are found) on a thread with a
+//! shared `zoom_channels::ZoomChannels`, and feed that into a `zoom_channels::ZoomSessionState` to get the statuses.
+//!
+//! `capture::ZoomChannelCapture::builder()` sets all that up for you, or to drive it by hand: capture settings all
+//! live in `CaptureOptions` and the status settings are public fields on `ZoomSessionState`, so set the ones you need
+//! and leave the rest at their defaults:
+//!
+//! ```no_run
+//! use std::sync::{Arc, RwLock};
+//! use std::time::Instant;
+//! use zoom_tally::custom_device::CustomDevice;
+//! use zoom_tally::stream_analyser::{CaptureOptions, PortDiscoveryCapture, PortRange};
+//! use zoom_tally::zoom_channels::{ZoomChannels, ZoomSessionState};
+//!
+//! let options = CaptureOptions {
+//!     zoom_ports: vec![PortRange {start: 8801, end: 8810}],
+//!     ..CaptureOptions::default()
+//! };
+//! let channels = Arc::new(RwLock::new(ZoomChannels::new()));
+//!
+//! let capture_channels = channels.clone();
+//! stoppable_thread::spawn(move |stopped| {
+//!     PortDiscoveryCapture::run(CustomDevice::default_device()?, options, capture_channels, stopped)
+//! });
+//!
+//! let mut session_state = ZoomSessionState::new();
+//! session_state.av_off_ms = 500;
+//! loop {
+//!     session_state.update_channels(&channels.read().unwrap(), Instant::now());
+//!     println!("Call: {}", session_state.call.as_str());
+//!     std::thread::sleep(std::time::Duration::from_millis(100));
+//! }
+//! ```
+
+pub mod stream_analyser;
+pub mod zoom_channels;
+pub mod custom_device;
+pub mod error;
+pub mod sink;
+pub mod capture;
@@ -0,0 +1,31 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::thread;
+
+/// Run a Shortcuts shortcut to switch a Focus mode (e.g. Do Not Disturb) on or off
+///
+/// The shortcut gets `on` or `off` as its input, so a single shortcut can handle both transitions. It runs on its own
+/// thread so a slow shortcut doesn't hold up status updates.
+pub fn run_focus_shortcut(shortcut: &str, on: bool) {
+    let shortcut = shortcut.to_string();
+    let input = if on { "on" } else { "off" };
+
+    thread::spawn(move || {
+        let child = Command::new("shortcuts")
+            .args(["run", shortcut.as_str(), "--input-path", "-"])
+            .stdin(Stdio::piped())
+            .spawn();
+
+        match child {
+            Ok(mut child) => {
+                if let Some(mut stdin) = child.stdin.take() {
+                    let _ = stdin.write_all(input.as_bytes());
+                }
+                if let Err(e) = child.wait() {
+                    eprintln!("Shortcut {:?} failed: {}", shortcut, e);
+                }
+            },
+            Err(e) => eprintln!("Couldn't run shortcut {:?}: {}", shortcut, e)
+        }
+    });
+}
@@ -6,22 +6,387 @@
 use std::thread;
 use std::sync::Arc;
 use std::sync::RwLock;
+use std::path::{Path, PathBuf};
+use std::io::IsTerminal;
+use std::str::FromStr;
+use std::time::Instant;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-use chrono::{Utc, Duration};
+use chrono::{Duration, Utc};
 use pcap::Device;
-use stoppable_thread;
 use enclose::enclose;
-use argparse::{ArgumentParser, StoreOption, StoreTrue};
+use stoppable_thread::SimpleAtomicBool;
+use argparse::{ArgumentParser, List, Store, StoreOption, StoreTrue};
 
-mod stream_analyser;
-mod zoom_channels;
-mod custom_device;
-use custom_device::CustomDevice;
+#[cfg(unix)]
+mod socket_server;
+#[cfg(windows)]
+mod pipe_server;
+#[cfg(all(target_os = "macos", feature = "macos-focus"))]
+mod macos_focus;
+#[cfg(feature = "hass")]
+mod hass;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+#[cfg(feature = "metrics")]
+mod metrics;
+#[cfg(all(target_os = "linux", feature = "identify-process"))]
+mod process_lookup;
+#[cfg(all(any(target_os = "linux", target_os = "macos"), feature = "notify"))]
+mod notify;
+use zoom_tally::stream_analyser;
+use zoom_tally::custom_device::CustomDevice;
+use zoom_tally::error::ZoomTallyError;
+use zoom_tally::sink::StatusSink;
+use zoom_tally::stream_analyser::{CaptureOptions, IpFamily, KeepalivePolicy, PortRange, SizeBucket, elapsed_since};
+use zoom_tally::zoom_channels::{ActivityPoint, CaptureMode, PortTiers, QualityBand, SessionEvent, SessionSummary, StatusProfile, StatusTimes, ZoomChannelStatus, ZoomChannels, ZoomSessionState};
 
+/// Exit code used when there are no network devices available to capture from
+const EXIT_NO_DEVICES: i32 = 3;
 
-fn parse_args() -> CustomDevice {
+/// Exit code used when a capture can't be opened or fails while running
+const EXIT_CAPTURE_FAILED: i32 = 4;
+
+/// Exit code used by `--once` when there's no call going on
+const EXIT_NOT_IN_CALL: i32 = 1;
+
+/// Default for how long `--once` watches for before reporting
+const DEFAULT_ONCE_SECS: u32 = 3;
+
+/// Default permissions for the `--socket`, letting any user read the statuses without needing to capture themselves
+#[cfg(unix)]
+const DEFAULT_SOCKET_MODE: u32 = 0o666;
+
+/// How long the startup self-test watches traffic for
+const SELF_TEST_SECS: i64 = 5;
+
+/// Version of the JSON output format, to be bumped whenever its fields change
+const SCHEMA_VERSION: u32 = 9;
+
+/// If nothing has been captured after this long, suggest promiscuous mode might help
+const NO_PACKETS_HINT_SECS: i64 = 30;
+
+/// How often `--stream-table` redraws the table
+const STREAM_TABLE_REFRESH_MSEC: i64 = 1000;
+
+/// How long to monitor a call for before going back to discovery for a while, to pick up ports that have appeared since
+const MONITOR_REDISCOVER_SECS: i64 = 30;
+
+/// How long to look for new ports when going back to discovery from monitor mode
+const REDISCOVER_SECS: i64 = 5;
+
+/// Monitoring plugin exit codes, as used by Nagios and Icinga
+const NAGIOS_OK: i32 = 0;
+const NAGIOS_WARNING: i32 = 1;
+const NAGIOS_CRITICAL: i32 = 2;
+
+/// How long `--nagios` watches for before reporting, so the channels have time to be found
+const NAGIOS_SETTLE_SECS: i64 = 10;
+
+/// If the capture loop hasn't gone round for this long, `--nagios` reports it as stuck
+const NAGIOS_CAPTURE_STALLED_SECS: i64 = 5;
+
+/// Set when errors should be reported as a monitoring plugin result rather than our own exit codes
+static NAGIOS_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+/// Report that there's nothing we can capture on, and exit
+fn exit_no_devices() -> ! {
+    if NAGIOS_OUTPUT.load(Ordering::SeqCst) {
+        println!("ZOOM CRITICAL - no capturable network devices found");
+        std::process::exit(NAGIOS_CRITICAL);
+    }
+    eprintln!("No capturable network devices found; is libpcap installed / do you have permission?");
+    std::process::exit(EXIT_NO_DEVICES);
+}
+
+/// Report an error that stops us capturing, and exit with a code for the kind of error
+fn exit_with_error(e: ZoomTallyError) -> ! {
+    if NAGIOS_OUTPUT.load(Ordering::SeqCst) {
+        println!("ZOOM CRITICAL - {}", e);
+        std::process::exit(NAGIOS_CRITICAL);
+    }
+    eprintln!("{}", e);
+    std::process::exit(match e {
+        ZoomTallyError::Device(_) => EXIT_NO_DEVICES,
+        ZoomTallyError::Capture(_) => EXIT_CAPTURE_FAILED
+    });
+}
+
+/// Look up the capture device, waiting for it to appear if `wait` is set, and exit if it can't be found
+fn find_device(wait: bool, mut lookup: impl FnMut() -> Result<CustomDevice, ZoomTallyError>) -> CustomDevice {
+    if wait {
+        // Nothing can stop the wait before we've started, other than Ctrl-C
+        stream_analyser::retry_until_ready(&SimpleAtomicBool::new(false), lookup).unwrap_or_else(|| exit_no_devices())
+    } else {
+        lookup().unwrap_or_else(|e| exit_with_error(e))
+    }
+}
+
+/// Set when the user asks us to stop, so the main loop can finish up cleanly
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn request_stop(_signal: libc::c_int) {
+    STOP_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Catch Ctrl-C and SIGTERM (e.g. from a process supervisor) so the main loop can finish up instead of being killed
+#[cfg(unix)]
+fn handle_interrupt() {
+    let handler: extern "C" fn(libc::c_int) = request_stop;
+    unsafe {
+        libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, handler as libc::sighandler_t);
+    }
+}
+
+/// Console control event sent for Ctrl-C
+#[cfg(windows)]
+const CTRL_C_EVENT: u32 = 0;
+/// Console control event sent for Ctrl-Break
+#[cfg(windows)]
+const CTRL_BREAK_EVENT: u32 = 1;
+
+#[cfg(windows)]
+#[link(name = "kernel32")]
+extern "system" {
+    fn SetConsoleCtrlHandler(handler: Option<extern "system" fn(u32) -> i32>, add: i32) -> i32;
+}
+
+/// Called by Windows on its own thread, returning non-zero if we've dealt with the event
+#[cfg(windows)]
+extern "system" fn request_stop(ctrl_type: u32) -> i32 {
+    match ctrl_type {
+        CTRL_C_EVENT | CTRL_BREAK_EVENT => {
+            STOP_REQUESTED.store(true, Ordering::SeqCst);
+            1
+        },
+        // Closing the console or logging off can't be held up, so leave those to the default handler
+        _ => 0
+    }
+}
+
+/// Catch Ctrl-C and Ctrl-Break in the console so the main loop can finish up instead of being killed
+#[cfg(windows)]
+fn handle_interrupt() {
+    if unsafe { SetConsoleCtrlHandler(Some(request_stop), 1) } == 0 {
+        eprintln!("Couldn't catch Ctrl-C, so stopping won't print a summary: {}", std::io::Error::last_os_error());
+    }
+}
+
+/// When to colour the status output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ColorChoice {
+    Always,
+    /// Only when stdout is a terminal, so logs and pipes don't get escape codes
+    Auto,
+    Never
+}
+
+impl ColorChoice {
+    /// Whether to colour output right now
+    fn enabled(&self) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Auto => std::io::stdout().is_terminal(),
+            ColorChoice::Never => false
+        }
+    }
+}
+
+impl FromStr for ColorChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(ColorChoice::Always),
+            "auto" => Ok(ColorChoice::Auto),
+            "never" => Ok(ColorChoice::Never),
+            _ => Err(format!("Unknown colour choice {:?}, expected always, auto or never", s))
+        }
+    }
+}
+
+/// How the status updates are written to stdout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    /// Human readable status lines
+    Text,
+    /// A JSON object per update and per event, one per line
+    Json
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!("Unknown output format {:?}, expected text or json", s))
+        }
+    }
+}
+
+/// Where packets come from
+enum CaptureSource {
+    /// Capture live from a network device
+    Live(CustomDevice),
+    /// Analyse saved pcap files one after another
+    Replay(Vec<PathBuf>)
+}
+
+/// Settings chosen on the command line
+struct Config {
+    source: CaptureSource,
+    capture_options: CaptureOptions,
+    /// Stay in discover mode rather than switching to monitor mode once ports are known
+    no_monitor: bool,
+    /// How long after its last packet the video or audio is reported as off
+    av_timeout_ms: Option<i64>,
+    /// How long after the last packet on any channel the call is reported as off
+    call_timeout_ms: Option<i64>,
+    /// How long the call must stay silent past the timeout before it's reported as off
+    call_linger_ms: Option<i64>,
+    /// How long after the last packet the call must wait before it's reported as off
+    call_end_grace_ms: Option<i64>,
+    /// How many checks in a row a channel must look off before it's reported as off
+    off_debounce: Option<u32>,
+    /// Take the call status from the control channel alone
+    use_control_for_call: bool,
+    /// Bitrate bands used to label the video quality
+    quality_bands: Vec<QualityBand>,
+    /// Curve mapping bitrate to activity level
+    activity_points: Vec<ActivityPoint>,
+    /// Report video bitrate steps bigger than this percentage
+    video_step_percent: Option<u32>,
+    /// How long a video bitrate step must last to be reported
+    video_step_confirm_ms: Option<i64>,
+    /// Print totals for each call as it ends, and for the run when it's stopped with Ctrl-C
+    summary: bool,
+    /// Stop after this many seconds and print how long each channel spent in each status
+    profile_secs: Option<u32>,
+    /// Colour the statuses in the status output
+    color: bool,
+    /// How to write status updates to stdout
+    format: OutputFormat,
+    /// Check the capture filter matches some traffic before starting
+    self_test: bool,
+    /// Just check this BPF filter compiles on the device, then exit
+    filter_test: Option<String>,
+    /// Show the raw call status alongside the debounced one
+    show_raw: bool,
+    /// Print the statuses and known streams every time round the loop, not just when something changes
+    verbose: bool,
+    /// Watch for a little while, then print a monitoring plugin result and exit
+    nagios: bool,
+    /// Watch for this many seconds, then print the statuses once and exit according to whether there's a call
+    once_secs: Option<u32>,
+    /// Don't print the `--once` result, just exit with it
+    quiet: bool,
+    /// Print the capture device's hardware and IP addresses at startup
+    show_addresses: bool,
+    /// Print a table of every stream being tracked instead of the statuses
+    stream_table: bool,
+    /// How often to print a heartbeat line showing the capture thread is still alive, if at all
+    heartbeat_secs: Option<u32>,
+    /// Path of a Unix socket to stream status lines to, if any
+    #[cfg(unix)]
+    socket_path: Option<String>,
+    /// Permissions to create the Unix socket with
+    #[cfg(unix)]
+    socket_mode: u32,
+    /// Name of a Windows named pipe to stream status lines to, if any
+    #[cfg(windows)]
+    pipe_name: Option<String>,
+    /// Name of a Shortcuts shortcut to run with `on` or `off` as a call starts and ends
+    #[cfg(all(target_os = "macos", feature = "macos-focus"))]
+    focus_shortcut: Option<String>,
+    /// Text for the desktop notifications when a call starts and ends, if they're turned on
+    #[cfg(all(any(target_os = "linux", target_os = "macos"), feature = "notify"))]
+    notify: Option<(String, String)>,
+    /// Home Assistant URL and access token to post the status to, if any
+    #[cfg(feature = "hass")]
+    hass: Option<(String, String)>,
+    /// MQTT broker and topic prefix to publish the statuses to, if any
+    #[cfg(feature = "mqtt")]
+    mqtt: Option<(String, String)>,
+    /// Address to serve Prometheus metrics on, if any
+    #[cfg(feature = "metrics")]
+    metrics_address: Option<String>,
+    /// Look up and print which process owns each channel's port
+    #[cfg(all(target_os = "linux", feature = "identify-process"))]
+    identify_process: bool
+}
+
+fn parse_args() -> Config {
     let mut list_devices: bool = false;
     let mut device_name: Option<String> = None;
+    let mut device_index: Option<usize> = None;
+    let mut capture_options = CaptureOptions::default();
+    let mut no_monitor: bool = false;
+    let mut heartbeat_secs: Option<u32> = None;
+    let mut profile_secs: Option<u32> = None;
+    let mut color_choice: Option<String> = None;
+    let mut format: Option<String> = None;
+    let mut no_color: bool = false;
+    let mut self_test: bool = false;
+    let mut filter_test: Option<String> = None;
+    let mut nagios: bool = false;
+    let mut show_addresses: bool = false;
+    let mut stream_table: bool = false;
+    let mut show_raw: bool = false;
+    let mut verbose: bool = false;
+    let mut once: bool = false;
+    let mut once_secs: Option<u32> = None;
+    let mut quiet: bool = false;
+    let mut av_timeout_ms: Option<i64> = None;
+    let mut call_timeout_ms: Option<i64> = None;
+    let mut call_linger_ms: Option<i64> = None;
+    let mut call_end_grace_ms: Option<i64> = None;
+    let mut off_debounce: Option<u32> = None;
+    let mut use_control_for_call: bool = false;
+    #[cfg(unix)]
+    let mut socket_path: Option<String> = None;
+    #[cfg(unix)]
+    let mut socket_mode: String = format!("{:o}", DEFAULT_SOCKET_MODE);
+    #[cfg(windows)]
+    let mut pipe_name: Option<String> = None;
+    let mut summary: bool = false;
+    #[cfg(all(target_os = "macos", feature = "macos-focus"))]
+    let mut focus_shortcut: Option<String> = None;
+    #[cfg(all(any(target_os = "linux", target_os = "macos"), feature = "notify"))]
+    let mut notify: bool = false;
+    #[cfg(all(any(target_os = "linux", target_os = "macos"), feature = "notify"))]
+    let mut notify_start_text: String = "Joined a Zoom call".to_string();
+    #[cfg(all(any(target_os = "linux", target_os = "macos"), feature = "notify"))]
+    let mut notify_end_text: String = "Call ended".to_string();
+    #[cfg(feature = "hass")]
+    let mut hass_url: Option<String> = None;
+    #[cfg(all(target_os = "linux", feature = "identify-process"))]
+    let mut identify_process: bool = false;
+    #[cfg(feature = "hass")]
+    let mut hass_token: Option<String> = None;
+    #[cfg(feature = "mqtt")]
+    let mut mqtt_broker: Option<String> = None;
+    #[cfg(feature = "mqtt")]
+    let mut mqtt_topic: String = "zoom-tally".to_string();
+    #[cfg(feature = "metrics")]
+    let mut metrics_address: Option<String> = None;
+    let mut size_buckets: Option<String> = None;
+    let mut video_threshold: Option<u16> = None;
+    let mut audio_threshold: Option<u16> = None;
+    let mut quality_bands: Option<String> = None;
+    let mut activity_points: Option<String> = None;
+    let mut video_step_percent: Option<u32> = None;
+    let mut video_step_confirm_ms: Option<i64> = None;
+    let mut port_range: Option<String> = None;
+    let mut prefer_ipv4: bool = false;
+    let mut prefer_ipv6: bool = false;
+    let mut no_keepalive_drop: bool = false;
+    let mut no_control_channel: bool = false;
+    let mut replay_paths: Vec<String> = Vec::new();
+    let mut local_ips: Vec<String> = Vec::new();
 
     {
         let mut parser = ArgumentParser::new();
@@ -30,93 +395,1457 @@ fn parse_args() -> CustomDevice {
         parser.refer(&mut device_name)
             .add_option(&["-d", "--device"], StoreOption, "Network device to capture from - will try to guess if not set");
 
+        parser.refer(&mut device_index)
+            .add_option(&["--device-index"], StoreOption, "Number of the network device to capture from, as shown by --list");
 
         parser.refer(&mut list_devices)
             .add_option(&["--list"], StoreTrue, "Just list network devices and exit");
 
+        parser.refer(&mut replay_paths)
+            .add_option(&["--replay", "--file"], List, "Analyse these pcap files (or directories of them) in turn instead of capturing, and print a summary of each");
+
+        parser.refer(&mut capture_options.trace_csv)
+            .add_option(&["--trace-csv"], StoreTrue, "Output a CSV line for every packet processed (timestamp,port,length,avg_size,class) instead of statuses");
+
+        parser.refer(&mut capture_options.capture_timeout_ms)
+            .add_option(&["--capture-timeout-ms"], Store, "How long pcap waits for packets before checking in (default 100) - lower uses more CPU, higher is slower to notice a channel going off");
+
+        parser.refer(&mut capture_options.power_save)
+            .add_option(&["--power-save"], StoreTrue, "After 30s without Zoom traffic, wait up to 1s for packets instead of the capture timeout, to save power - the first packets of a call may be seen up to a second late");
+
+        parser.refer(&mut capture_options.buffer_size)
+            .add_option(&["--buffer-size"], StoreOption, "Size of the capture buffer in bytes - increase if packets are being dropped on a busy link (default is pcap's own)");
+
+        parser.refer(&mut capture_options.snaplen)
+            .add_option(&["--snaplen"], StoreOption, "Bytes of each packet to capture - by default this is worked out from the link type so headers are never cut short");
+
+        parser.refer(&mut capture_options.stats_interval_secs)
+            .add_option(&["--stats-interval"], StoreOption, "Log the capture's received and dropped packet counts to stderr every this many seconds");
+
+        parser.refer(&mut size_buckets)
+            .add_option(&["--size-buckets"], StoreOption, "Average packet sizes used to classify streams, e.g. the default 500:video,90:audio,0:control");
+
+        parser.refer(&mut video_threshold)
+            .add_option(&["--video-threshold"], StoreOption, "Average packet size a stream must be above to be video (default 500), a shortcut for --size-buckets");
+
+        parser.refer(&mut audio_threshold)
+            .add_option(&["--audio-threshold"], StoreOption, "Average packet size a stream must be above to be audio (default 90), a shortcut for --size-buckets");
+
+        parser.refer(&mut capture_options.wait_for_device)
+            .add_option(&["--wait-for-device"], StoreTrue, "If the device doesn't exist or isn't up yet, keep retrying with backoff rather than exiting, e.g. when started at boot");
+
+        parser.refer(&mut capture_options.ema_alpha)
+            .add_option(&["--ema-alpha"], Store, "Weight each packet gets in a stream's moving averages, from 0 to 1 (default 0.1). Higher reacts faster, lower is steadier");
+
+        parser.refer(&mut capture_options.calibrate)
+            .add_option(&["--calibrate"], StoreTrue, "Once a call's found, watch its streams for 5s and replace the size buckets with thresholds halfway between the sizes seen, before switching to monitor mode (gives up after 30s if there aren't three different sizes)");
+
+        parser.refer(&mut quality_bands)
+            .add_option(&["--video-quality-bands"], StoreOption, "Video bitrates in kbps used to label the quality, e.g. the default 1200:hd,400:sd,0:low - video has to be above a band's bitrate to get its label, apart from a band at 0 which takes everything else");
+
+        parser.refer(&mut activity_points)
+            .add_option(&["--activity-levels"], StoreOption, "Combined audio and video kbps to map to each activity level from 0-100, interpolating in between, e.g. the default 0:0,40:30,1000:80,2500:100");
+
+        parser.refer(&mut video_step_percent)
+            .add_option(&["--video-step-percent"], StoreOption, "Report a video_bitrate_changed event when the video bitrate moves by more than this percentage and stays there, e.g. from switching view");
+
+        parser.refer(&mut video_step_confirm_ms)
+            .add_option(&["--video-step-confirm-ms"], StoreOption, "How long the video bitrate must stay moved before --video-step-percent reports it (default 3000)");
+
+        parser.refer(&mut port_range)
+            .add_option(&["--port", "--port-range"], StoreOption, "Comma separated destination ports or port ranges Zoom traffic goes to, e.g. 8801,8802 or 8801-8810 (default 8801)");
+
+        parser.refer(&mut prefer_ipv4)
+            .add_option(&["--prefer-ipv4"], StoreTrue, "Only watch IPv4 traffic, for dual-stack networks where Zoom uses both (default is both)");
+
+        parser.refer(&mut prefer_ipv6)
+            .add_option(&["--prefer-ipv6"], StoreTrue, "Only watch IPv6 traffic, for dual-stack networks where Zoom uses both (default is both)");
+
+        parser.refer(&mut no_keepalive_drop)
+            .add_option(&["--no-keepalive-drop"], StoreTrue, "Count small keepalive packets in each stream's average too, to see the unfiltered sizes when debugging classification");
+
+        parser.refer(&mut no_control_channel)
+            .add_option(&["--no-control-channel"], StoreTrue, "Don't look for a separate control channel, for apps that keep the call alive within the audio stream - the call status then comes from audio and video only");
+
+        parser.refer(&mut capture_options.promisc)
+            .add_option(&["--promisc"], StoreTrue, "Capture in promiscuous mode - try this if nothing is seen on a VM or bridged network interface");
+
+        parser.refer(&mut capture_options.own_only)
+            .add_option(&["--own-only"], StoreTrue, "Only detect calls from this machine, ignoring Zoom traffic from other hosts seen on the link");
+
+        parser.refer(&mut local_ips)
+            .add_option(&["--local-ip"], List, "Treat this address as our own for --own-only instead of the device's addresses, e.g. a VPN address on another interface. Can be given more than once, and implies --own-only");
+
+        parser.refer(&mut capture_options.reclassify_cooldown_ms)
+            .add_option(&["--reclassify-cooldown-ms"], Store, "Once a port is classified, don't move it to another channel for this long unless its average changes a lot (default 0, off)");
+
+        parser.refer(&mut capture_options.log_reassignments)
+            .add_option(&["--log-reassignments"], StoreTrue, "Print a line to stderr each time a port is reclassified as a different channel, to spot flapping");
+
+        parser.refer(&mut no_monitor)
+            .add_option(&["--no-monitor"], StoreTrue, "Keep classifying all traffic in discover mode, never switch to monitor mode");
+
+        parser.refer(&mut av_timeout_ms)
+            .add_option(&["--av-timeout-ms"], StoreOption, "How long after its last packet the video or audio is reported as off - raise it on jittery Wi-Fi (default 200)");
+
+        parser.refer(&mut call_timeout_ms)
+            .add_option(&["--call-timeout-ms"], StoreOption, "How long after the last packet on any channel the call is reported as off, before --call-linger-ms (default 3000)");
+
+        parser.refer(&mut call_linger_ms)
+            .add_option(&["--call-linger-ms"], StoreOption, "How long the call must stay silent past the timeout before it's reported as off (default 1000)");
+
+        parser.refer(&mut call_end_grace_ms)
+            .add_option(&["--call-end-grace-ms"], StoreOption, "Keep the call on for at least this long after the last packet on any port, including teardown packets too few to classify, so a final exchange doesn't get cut off (default 0)");
+
+        parser.refer(&mut off_debounce)
+            .add_option(&["--off-debounce"], StoreOption, "How many checks in a row (one every 100ms) the video or audio must look off before it's reported off, so dropped packets don't make it flap (default 3)");
+
+        parser.refer(&mut use_control_for_call)
+            .add_option(&["--use-control-for-call"], StoreTrue, "Report the call as on while the control channel is sending, regardless of audio and video");
+
+        parser.refer(&mut profile_secs)
+            .add_option(&["--profile"], StoreOption, "Run for this many seconds, then print how long the call, video and audio spent in each status and how many times ports were reassigned, and exit");
+
+        parser.refer(&mut format)
+            .add_option(&["--format"], StoreOption, "How to write status updates: text (the default) or json, for a JSON object per line that tools like jq can read");
+
+        parser.refer(&mut color_choice)
+            .add_option(&["--color"], StoreOption, "Colour the statuses: always, auto (only when printing to a terminal) or never (default auto)");
+
+        parser.refer(&mut no_color)
+            .add_option(&["--no-color"], StoreTrue, "Never colour the statuses, same as --color never");
+
+        parser.refer(&mut self_test)
+            .add_option(&["--self-test"], StoreTrue, "Watch traffic for 5s before starting and warn if the device is busy but nothing matches the Zoom filter");
+
+        parser.refer(&mut filter_test)
+            .add_option(&["--filter-test"], StoreOption, "Check a BPF filter expression compiles on the selected device, print pcap's error if not, and exit (0 if it's valid, 1 if not)");
+
+        parser.refer(&mut capture_options.key_by_destination)
+            .add_option(&["--key-by-destination"], StoreTrue, "Tell streams apart by source port and destination address, rather than just source port, for networks where one port sends to several servers");
+
+        parser.refer(&mut stream_table)
+            .add_option(&["--stream-table"], StoreTrue, "Instead of the statuses, print a table of every stream being tracked each second, with its average size, window fill, last seen age and classification");
+
+        parser.refer(&mut show_addresses)
+            .add_option(&["--show-addresses"], StoreTrue, "Print the capture device's MAC and IP addresses at startup, to check it's the right one");
+
+        parser.refer(&mut nagios)
+            .add_option(&["--nagios"], StoreTrue, "Watch for 10s, then print a Nagios/Icinga plugin result (e.g. ZOOM OK - in call, video on, audio on | video_kbps=1200 audio_kbps=60) and exit 0, 1 or 2");
+
+        parser.refer(&mut show_raw)
+            .add_option(&["--show-raw"], StoreTrue, "Also output the call status before --call-linger-ms and --call-end-grace-ms are applied, and the video, audio and screen share before --off-debounce, to see what they're smoothing over");
+
+        parser.refer(&mut verbose)
+            .add_option(&["-v", "--verbose"], StoreTrue, "Print the statuses and known streams (or send the JSON status) every time they're checked, rather than only when they change");
+
+        parser.refer(&mut once)
+            .add_option(&["--once"], StoreTrue, "Watch for a few seconds, print the statuses once (as text, or JSON with --format json) and exit 0 if there's a call going on, 1 if not");
+
+        parser.refer(&mut once_secs)
+            .add_option(&["--once-secs"], StoreOption, "How long --once watches for before reporting (default 3)");
+
+        parser.refer(&mut quiet)
+            .add_option(&["-q", "--quiet"], StoreTrue, "Don't print the --once result, just exit with it, e.g. for if zoom-tally --once --quiet; then ...");
+
+        parser.refer(&mut heartbeat_secs)
+            .add_option(&["--heartbeat"], StoreOption, "Print a heartbeat line every this many seconds, to show capture is still running - with JSON output, the socket or the pipe, send the status again if it hasn't been sent for this long");
+
+        #[cfg(unix)]
+        parser.refer(&mut socket_path)
+            .add_option(&["--socket"], StoreOption, "Listen on this Unix socket path and stream a JSON status line to each connected client");
+
+        #[cfg(unix)]
+        parser.refer(&mut socket_mode)
+            .add_option(&["--socket-mode"], Store, "Octal permissions for the --socket, e.g. 660 to only let the socket's group connect (default 666, so unprivileged users can read the statuses)");
+
+        #[cfg(windows)]
+        parser.refer(&mut pipe_name)
+            .add_option(&["--pipe"], StoreOption, "Serve this named pipe (e.g. \\\\.\\pipe\\zoomtally) and stream a JSON status line to each connected client");
+
+        parser.refer(&mut summary)
+            .add_option(&["--summary"], StoreTrue, "As each call ends, and when stopped with Ctrl-C (or SIGTERM, or Ctrl-Break on Windows), print the time in calls, on camera and with the mic on, and the peak packet sizes");
+
+        #[cfg(all(target_os = "macos", feature = "macos-focus"))]
+        parser.refer(&mut focus_shortcut)
+            .add_option(&["--macos-focus"], StoreOption, "Run this Shortcuts shortcut with input \"on\" when a call starts and \"off\" when it ends, e.g. to toggle a Focus mode");
+
+        #[cfg(all(any(target_os = "linux", target_os = "macos"), feature = "notify"))]
+        parser.refer(&mut notify)
+            .add_option(&["--notify"], StoreTrue, "Show a desktop notification when a call starts and ends");
+
+        #[cfg(all(any(target_os = "linux", target_os = "macos"), feature = "notify"))]
+        parser.refer(&mut notify_start_text)
+            .add_option(&["--notify-start-text"], Store, "Text of the --notify notification when a call starts (default \"Joined a Zoom call\")");
+
+        #[cfg(all(any(target_os = "linux", target_os = "macos"), feature = "notify"))]
+        parser.refer(&mut notify_end_text)
+            .add_option(&["--notify-end-text"], Store, "Text of the --notify notification when a call ends, where {duration} is replaced with how long it lasted (default \"Call ended\")");
+
+        #[cfg(feature = "hass")]
+        parser.refer(&mut hass_url)
+            .add_option(&["--hass-url"], StoreOption, "Post the status to sensor.zoom_status on the Home Assistant at this http:// URL whenever it changes");
+
+        #[cfg(feature = "hass")]
+        parser.refer(&mut hass_token)
+            .add_option(&["--hass-token"], StoreOption, "Long-lived access token for --hass-url");
+
+        #[cfg(feature = "mqtt")]
+        parser.refer(&mut mqtt_broker)
+            .add_option(&["--mqtt-broker"], StoreOption, "Publish the statuses as retained messages to the MQTT broker at this host[:port] whenever they change");
+
+        #[cfg(feature = "mqtt")]
+        parser.refer(&mut mqtt_topic)
+            .add_option(&["--mqtt-topic"], Store, "Prefix for the --mqtt-broker topics, e.g. zoom-tally/video (default zoom-tally)");
+
+        #[cfg(feature = "metrics")]
+        parser.refer(&mut metrics_address)
+            .add_option(&["--metrics-addr"], StoreOption, "Serve Prometheus metrics at /metrics on this address, e.g. 127.0.0.1:9185");
+
+        #[cfg(all(target_os = "linux", feature = "identify-process"))]
+        parser.refer(&mut identify_process)
+            .add_option(&["--identify-process"], StoreTrue, "Print which process owns each channel's port, to check it really is Zoom (may need root to see other users' processes)");
+
         parser.parse_args_or_exit();
     }
 
+    // From here on failures need to be reported as a plugin result
+    NAGIOS_OUTPUT.store(nagios, Ordering::SeqCst);
+
+    if nagios && capture_options.trace_csv {
+        eprintln!("--nagios and --trace-csv both need stdout, so can't be used together");
+        std::process::exit(2);
+    }
+
+    if stream_table && (nagios || capture_options.trace_csv) {
+        eprintln!("--stream-table can't be used with --nagios or --trace-csv, as they all need stdout");
+        std::process::exit(2);
+    }
+
+    if once && (nagios || stream_table || capture_options.trace_csv) {
+        eprintln!("--once can't be used with --nagios, --stream-table or --trace-csv, as they all need stdout");
+        std::process::exit(2);
+    }
+
+    if !once && (once_secs.is_some() || quiet) {
+        eprintln!("--once-secs and --quiet only apply to --once");
+        std::process::exit(2);
+    }
+
+    if once_secs == Some(0) {
+        eprintln!("--once-secs must be greater than zero");
+        std::process::exit(2);
+    }
+
+    // A zero timeout means pcap blocks until a packet arrives, so we'd never notice a quiet link
+    if capture_options.capture_timeout_ms <= 0 {
+        eprintln!("--capture-timeout-ms must be greater than zero");
+        std::process::exit(2);
+    }
+
+    let color_choice = match color_choice {
+        Some(_) if no_color => {
+            eprintln!("--color and --no-color can't be used together");
+            std::process::exit(2);
+        },
+        Some(color_choice) => color_choice.parse().unwrap_or_else(|e| {
+            eprintln!("Invalid --color: {}", e);
+            std::process::exit(2);
+        }),
+        None if no_color => ColorChoice::Never,
+        None => ColorChoice::Auto
+    };
+
+    let format = match format {
+        Some(format) => format.parse().unwrap_or_else(|e| {
+            eprintln!("Invalid --format: {}", e);
+            std::process::exit(2);
+        }),
+        None => OutputFormat::Text
+    };
+
+    if format == OutputFormat::Json && (nagios || stream_table || capture_options.trace_csv) {
+        eprintln!("--format json can't be used with --nagios, --stream-table or --trace-csv, as they all need stdout");
+        std::process::exit(2);
+    }
+
+    if let Some(size_buckets) = size_buckets {
+        if video_threshold.is_some() || audio_threshold.is_some() {
+            eprintln!("--video-threshold and --audio-threshold can't be used with --size-buckets");
+            std::process::exit(2);
+        }
+        capture_options.size_buckets = SizeBucket::parse_list(&size_buckets).unwrap_or_else(|e| {
+            eprintln!("Invalid --size-buckets: {}", e);
+            std::process::exit(2);
+        });
+    } else if video_threshold.is_some() || audio_threshold.is_some() {
+        capture_options.size_buckets = SizeBucket::with_thresholds(video_threshold, audio_threshold).unwrap_or_else(|e| {
+            eprintln!("Invalid --video-threshold or --audio-threshold: {}", e);
+            std::process::exit(2);
+        });
+    }
+
+    let quality_bands = match quality_bands {
+        Some(quality_bands) => QualityBand::parse_list(&quality_bands).unwrap_or_else(|e| {
+            eprintln!("Invalid --video-quality-bands: {}", e);
+            std::process::exit(2);
+        }),
+        None => QualityBand::defaults()
+    };
+
+    if no_control_channel && use_control_for_call {
+        eprintln!("--use-control-for-call needs a control channel, so can't be used with --no-control-channel");
+        std::process::exit(2);
+    }
+    capture_options.control_channel = !no_control_channel;
+
+    if no_keepalive_drop {
+        capture_options.keepalive_policy = KeepalivePolicy::Include;
+    }
+
+    let activity_points = match activity_points {
+        Some(activity_points) => ActivityPoint::parse_list(&activity_points).unwrap_or_else(|e| {
+            eprintln!("Invalid --activity-levels: {}", e);
+            std::process::exit(2);
+        }),
+        None => ActivityPoint::defaults()
+    };
+
+    capture_options.ip_family = match (prefer_ipv4, prefer_ipv6) {
+        (true, true) => {
+            eprintln!("--prefer-ipv4 and --prefer-ipv6 can't be used together");
+            std::process::exit(2);
+        },
+        (true, false) => Some(IpFamily::V4),
+        (false, true) => Some(IpFamily::V6),
+        (false, false) => None
+    };
+
+    if let Some(port_range) = port_range {
+        capture_options.zoom_ports = PortRange::parse_list(&port_range).unwrap_or_else(|e| {
+            eprintln!("Invalid --port: {}", e);
+            std::process::exit(2);
+        });
+    }
+
+    if capture_options.snaplen.is_some_and(|snaplen| snaplen <= 0) {
+        eprintln!("--snaplen must be greater than zero");
+        std::process::exit(2);
+    }
+
+    if capture_options.buffer_size.is_some_and(|size| size <= 0) {
+        eprintln!("--buffer-size must be greater than zero");
+        std::process::exit(2);
+    }
+
     if list_devices {
         println!("Network devices:");
 
-        let device_list = Device::list().unwrap();
-        for device in device_list {
-            println!("{}", device.name);
+        let device_list = match Device::list() {
+            Ok(list) if !list.is_empty() => list,
+            _ => exit_no_devices()
+        };
+        for (index, device) in device_list.into_iter().enumerate() {
+            let name = device.name.clone();
+            let addresses: Vec<String> = CustomDevice::from(device).addresses().iter().map(|address| address.to_string()).collect();
+            if addresses.is_empty() {
+                println!("{}: {}", index, name);
+            } else {
+                println!("{}: {} ({})", index, name, addresses.join(", "));
+            }
         }
 
         std::process::exit(0);
     }
 
-    let capture_device = match device_name {
-        Some(name) => CustomDevice::device_from_name(name),
-        None => CustomDevice::from(Device::lookup().unwrap())
+    if av_timeout_ms.is_some_and(|timeout| timeout <= 0) || call_timeout_ms.is_some_and(|timeout| timeout <= 0) {
+        eprintln!("--av-timeout-ms and --call-timeout-ms must be greater than zero");
+        std::process::exit(2);
+    }
+
+    #[cfg(unix)]
+    let socket_mode = match u32::from_str_radix(&socket_mode, 8) {
+        Ok(mode) if mode <= 0o777 => mode,
+        _ => {
+            eprintln!("--socket-mode {:?} should be octal permissions like 660", socket_mode);
+            std::process::exit(2);
+        }
+    };
+
+    if call_linger_ms.is_some_and(|linger| linger < 0) {
+        eprintln!("--call-linger-ms can't be negative");
+        std::process::exit(2);
+    }
+
+    #[cfg(feature = "hass")]
+    let hass = match (hass_url, hass_token) {
+        (Some(url), Some(token)) => Some((url, token)),
+        (None, None) => None,
+        _ => {
+            eprintln!("--hass-url and --hass-token must be used together");
+            std::process::exit(2);
+        }
     };
 
-    return capture_device
+    if !(capture_options.ema_alpha > 0.0 && capture_options.ema_alpha <= 1.0) {
+        eprintln!("--ema-alpha must be more than 0 and at most 1");
+        std::process::exit(2);
+    }
+
+    if video_step_confirm_ms.is_some_and(|confirm| confirm < 0) {
+        eprintln!("--video-step-confirm-ms can't be negative");
+        std::process::exit(2);
+    }
+
+    if call_end_grace_ms.is_some_and(|grace| grace < 0) {
+        eprintln!("--call-end-grace-ms can't be negative");
+        std::process::exit(2);
+    }
+
+    if off_debounce == Some(0) {
+        eprintln!("--off-debounce must be at least 1");
+        std::process::exit(2);
+    }
+
+    for local_ip in &local_ips {
+        let address = local_ip.parse().unwrap_or_else(|_| {
+            eprintln!("--local-ip {:?} isn't an IP address", local_ip);
+            std::process::exit(2);
+        });
+        capture_options.local_addresses.push(address);
+        capture_options.own_only = true;
+    }
+
+    let source = if !replay_paths.is_empty() {
+        CaptureSource::Replay(replay_files(&replay_paths))
+    } else {
+        let capture_device = match (device_name, device_index) {
+            (Some(_), Some(_)) => {
+                eprintln!("--device and --device-index can't be used together");
+                std::process::exit(2);
+            },
+            (Some(name), None) => find_device(capture_options.wait_for_device, || CustomDevice::device_from_name(name.clone())),
+            (None, Some(index)) => find_device(capture_options.wait_for_device, || CustomDevice::from_index(index)),
+            (None, None) if capture_options.wait_for_device => find_device(true, CustomDevice::default_device),
+            (None, None) => match CustomDevice::default_device() {
+                Ok(device) => device,
+                Err(_) => exit_no_devices()
+            }
+        };
+
+        if capture_options.own_addresses(&capture_device).is_some_and(|addresses| addresses.is_empty()) {
+            eprintln!("--own-only needs an IP address on the capture device, but none were found - try --local-ip");
+            std::process::exit(2);
+        }
+
+        CaptureSource::Live(capture_device)
+    };
+
+    Config {
+        source,
+        capture_options,
+        no_monitor,
+        av_timeout_ms,
+        call_timeout_ms,
+        call_linger_ms,
+        call_end_grace_ms,
+        off_debounce,
+        use_control_for_call,
+        quality_bands,
+        activity_points,
+        video_step_percent,
+        video_step_confirm_ms,
+        summary,
+        profile_secs,
+        color: color_choice.enabled(),
+        format,
+        self_test,
+        filter_test,
+        show_raw,
+        verbose,
+        once_secs: if once { Some(once_secs.unwrap_or(DEFAULT_ONCE_SECS)) } else { None },
+        quiet,
+        nagios,
+        show_addresses,
+        stream_table,
+        heartbeat_secs,
+        #[cfg(unix)]
+        socket_path,
+        #[cfg(unix)]
+        socket_mode,
+        #[cfg(windows)]
+        pipe_name,
+        #[cfg(all(target_os = "macos", feature = "macos-focus"))]
+        focus_shortcut,
+        #[cfg(all(any(target_os = "linux", target_os = "macos"), feature = "notify"))]
+        notify: if notify { Some((notify_start_text, notify_end_text)) } else { None },
+        #[cfg(feature = "hass")]
+        hass,
+        #[cfg(feature = "mqtt")]
+        mqtt: mqtt_broker.map(|broker| (broker, mqtt_topic)),
+        #[cfg(feature = "metrics")]
+        metrics_address,
+        #[cfg(all(target_os = "linux", feature = "identify-process"))]
+        identify_process
+    }
+}
+
+/// Expand the paths given to `--replay` into a list of files, replacing each directory with the pcap files in it
+fn replay_files(paths: &[String]) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+
+    for path in paths.iter().map(PathBuf::from) {
+        if path.is_dir() {
+            let entries = std::fs::read_dir(&path).unwrap_or_else(|e| {
+                eprintln!("Couldn't read directory {}: {}", path.display(), e);
+                std::process::exit(2);
+            });
+
+            let mut dir_files: Vec<PathBuf> = entries.flatten()
+                .map(|entry| entry.path())
+                .filter(|file| file.extension().is_some_and(|ext| ext == "pcap" || ext == "pcapng"))
+                .collect();
+            dir_files.sort();
+            files.extend(dir_files);
+        } else {
+            files.push(path);
+        }
+    }
+
+    if files.is_empty() {
+        eprintln!("No pcap files found to replay");
+        std::process::exit(2);
+    }
+
+    files
+}
+
+/// Forget the streams that haven't sent anything within the call timeout, returning whether any are left to monitor
+///
+/// Without this, each call's ports would be added to the monitor filter for as long as we run.
+fn forget_idle_streams(channel_status: &RwLock<ZoomChannels>, now: Instant, call_timeout: Duration) -> bool {
+    let mut write_map = channel_status.write().unwrap();
+    write_map.forget_idle_streams(now, call_timeout);
+    !stream_analyser::monitored_streams(&write_map).is_empty()
+}
+
+/// Describe the stream found for a channel, for the replay summary
+fn describe_stream(stream: Option<stream_analyser::PacketStream>) -> String {
+    match stream {
+        Some(stream) => match stream.destination() {
+            Some(destination) => format!("port {} to {} (avg size {})", stream.source_port(), destination, stream.average_packet_size()),
+            None => format!("port {} (avg size {})", stream.source_port(), stream.average_packet_size())
+        },
+        None => "not found".to_string()
+    }
+}
+
+/// Run discovery over a saved capture file and print a summary of what was found
+///
+/// Statuses are worked out as of the last packet in the file, each file starting from a clean state.
+fn print_replay_summary(path: &Path, capture_options: &CaptureOptions, config: &Config) {
+    let channels = match stream_analyser::PortDiscoveryCapture::replay(path, capture_options) {
+        Ok(channels) => channels,
+        Err(e) => {
+            eprintln!("Couldn't replay {}: {}", path.display(), e);
+            return;
+        }
+    };
+
+    let mut session_state = new_session_state(config);
+    let last_packet_seen = [channels.control, channels.audio, channels.video].iter()
+        .flatten()
+        .map(|stream| stream.last_seen().seen_at)
+        .max()
+        .unwrap_or_else(Instant::now);
+    session_state.update_channels(&channels, last_packet_seen);
+
+    println!("{}:", path.display());
+    println!("  Packets: {} processed, {} undecodable, {} STUN", channels.packets_processed, channels.undecodable_packets,
+        channels.stun_packets);
+    println!("  Video: {}", describe_stream(channels.video));
+    println!("  Audio: {}", describe_stream(channels.audio));
+    println!("  Control: {}", describe_stream(channels.control));
+    println!("  Screen share: {}", describe_stream(channels.screenshare));
+    println!("  Final statuses: Call: {} Video: {} Audio: {} Camera live: {}",
+        session_state.call.as_str(), session_state.video.as_str(), session_state.audio.as_str(), session_state.camera_live);
+    println!("  Reassignments: {}", channels.reassignments);
+    println!("  Ports: {}", describe_ports_seen(&channels));
+    if let Some(buckets) = &channels.calibrated_buckets {
+        println!("  Calibrated: {}", CaptureOptions { size_buckets: buckets.clone(), ..capture_options.clone() }.describe_classification());
+    }
+}
+
+/// Create a session state with the call settings chosen on the command line
+fn new_session_state(config: &Config) -> ZoomSessionState {
+    let mut session_state = ZoomSessionState::new();
+    if let Some(av_timeout_ms) = config.av_timeout_ms {
+        session_state.av_off_ms = av_timeout_ms;
+    }
+    if let Some(call_timeout_ms) = config.call_timeout_ms {
+        session_state.call_timeout_ms = call_timeout_ms;
+    }
+    if let Some(call_linger_ms) = config.call_linger_ms {
+        session_state.call_linger_ms = call_linger_ms;
+    }
+    if let Some(call_end_grace_ms) = config.call_end_grace_ms {
+        session_state.call_end_grace_ms = call_end_grace_ms;
+    }
+    if let Some(off_debounce) = config.off_debounce {
+        session_state.off_debounce = off_debounce;
+    }
+    session_state.use_control_for_call = config.use_control_for_call;
+    session_state.quality_bands = config.quality_bands.clone();
+    session_state.activity_points = config.activity_points.clone();
+    session_state.video_step_percent = config.video_step_percent;
+    if let Some(video_step_confirm_ms) = config.video_step_confirm_ms {
+        session_state.video_step_confirm_ms = video_step_confirm_ms;
+    }
+    session_state
+}
+
+/// Describe the camera and mic times and peak packet sizes in a summary, for the reports at the end of a call and of
+/// the run
+fn describe_summary(summary: &SessionSummary) -> String {
+    format!("{}s on camera, {}s with mic on, peak average packet size video {}B audio {}B control {}B",
+        summary.camera_time.num_seconds(), summary.mic_time.num_seconds(),
+        summary.peak_video_packet_size, summary.peak_audio_packet_size, summary.peak_control_packet_size)
+}
+
+/// Print the totals for the run
+fn print_summary(summary: &SessionSummary) {
+    println!("Summary: {} calls, {}s in calls, {}", summary.calls, summary.call_time.num_seconds(), describe_summary(summary));
+}
+
+/// Check the capture filter matches some of the device's traffic, and warn if it looks like it never will
+fn run_self_test(capture_device: &CustomDevice, capture_options: &CaptureOptions) {
+    eprintln!("Self test: watching traffic for {}s...", SELF_TEST_SECS);
+    let check = stream_analyser::check_filter(capture_device.clone(), capture_options, Duration::seconds(SELF_TEST_SECS))
+        .unwrap_or_else(|e| exit_with_error(e));
+
+    if check.received == 0 {
+        eprintln!("Self test: no traffic at all on this device - is it the right one? Try --list");
+    } else if check.matched == 0 {
+        eprintln!("Self test: {} packets seen but none matched the Zoom filter - if you're in a call, check --port, \
+            --prefer-ipv4/--prefer-ipv6 and --own-only", check.received);
+    } else {
+        eprintln!("Self test: {} of {} packets matched the Zoom filter", check.matched, check.received);
+    }
+}
+
+/// Compile a BPF filter on the device, report whether it's valid and exit accordingly
+fn run_filter_test(capture_device: &CustomDevice, capture_options: &CaptureOptions, filter: &str) -> ! {
+    match stream_analyser::compile_filter(capture_device.clone(), capture_options, filter) {
+        Ok(compiled) => {
+            println!("Filter is valid: {}", compiled);
+            std::process::exit(0);
+        },
+        Err(e) => {
+            eprintln!("Filter is not valid: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Print a device's hardware and IP addresses
+fn print_addresses(capture_device: &CustomDevice) {
+    let addresses: Vec<String> = capture_device.addresses().iter().map(|address| address.to_string()).collect();
+    println!("Device addresses: MAC: {} IP: {}", capture_device.mac_address().unwrap_or("unknown"),
+        if addresses.is_empty() { "none".to_string() } else { addresses.join(", ") });
+}
+
+/// Print a row for every stream the capture thread is tracking, with its classification
+///
+/// # Arguments
+/// * `channels` - Channels and streams known from the capture thread
+/// * `now` - Time to work out how long ago each stream was last seen
+/// * `redraw` - Clear the terminal first, so the table refreshes in place
+fn print_stream_table(channels: &ZoomChannels, now: Instant, redraw: bool) {
+    if redraw {
+        print!("\x1b[2J\x1b[H");
+    }
+
+    println!("{:>6}  {:<39}  {:>8}  {:>6}  {:>10}  {:<7}", "port", "destination", "avg size", "window", "last seen", "class");
+    for stream in channels.streams.values() {
+        println!("{:>6}  {:<39}  {:>7}B  {:>3}/{:<2}  {:>8}ms  {:<7}",
+            stream.source_port(),
+            stream.destination().map_or("-".to_string(), |destination| destination.to_string()),
+            stream.average_packet_size(),
+            stream.window_fill(), stream_analyser::BITRATE_WINDOW_SIZE,
+            elapsed_since(now, stream.last_seen().seen_at).num_milliseconds(),
+            channels.channel_for_stream(stream).map_or("-", |kind| kind.as_str()));
+    }
+    println!();
+}
+
+/// Work out the monitoring plugin exit code and result line for the session
+///
+/// Being in a call or not are both fine, but a capture that's stopped responding is critical, and traffic we can't
+/// make sense of is a warning.
+fn nagios_result(session_state: &ZoomSessionState, now: Instant) -> (i32, String) {
+    let channels = &session_state.channels;
+    let kbps = |stream: Option<stream_analyser::PacketStream>, status: ZoomChannelStatus| {
+        stream.filter(|_| status == ZoomChannelStatus::On).and_then(|stream| stream.bitrate_kbps()).unwrap_or(0)
+    };
+    let perfdata = format!("video_kbps={} audio_kbps={}", kbps(channels.video, session_state.video),
+        kbps(channels.audio, session_state.audio));
+
+    let capture_idle = elapsed_since(now, channels.last_capture_cycle);
+    let (code, label, summary) = if capture_idle >= Duration::seconds(NAGIOS_CAPTURE_STALLED_SECS) {
+        (NAGIOS_CRITICAL, "CRITICAL", format!("capture hasn't responded for {}s", capture_idle.num_seconds()))
+    } else if session_state.call == ZoomChannelStatus::Unknown {
+        (NAGIOS_WARNING, "WARNING", "Zoom traffic seen but the call status is unknown".to_string())
+    } else if session_state.is_in_meeting() {
+        (NAGIOS_OK, "OK", format!("in call, video {}, audio {}", session_state.video.as_str(), session_state.audio.as_str()))
+    } else {
+        (NAGIOS_OK, "OK", "not in call".to_string())
+    };
+
+    (code, format!("ZOOM {} - {} | {}", label, summary, perfdata))
+}
+
+/// Format an event as a single line JSON object
+fn event_json(event: &SessionEvent) -> String {
+    let mut fields = vec![
+        ("schema_version", SCHEMA_VERSION.to_string()),
+        ("ts", format!("\"{}\"", Utc::now().to_rfc3339())),
+        ("event", format!("\"{}\"", event.as_str()))
+    ];
+
+    if let SessionEvent::VideoBitrateChanged { from_kbps, to_kbps } = event {
+        fields.push(("from_kbps", from_kbps.to_string()));
+        fields.push(("to_kbps", to_kbps.to_string()));
+    }
+
+    json_object(&fields)
+}
+
+/// Format a status for the status output, coloured green for on, red for off and yellow otherwise if `color` is set
+fn paint_status(status: ZoomChannelStatus, color: bool) -> String {
+    let text = format!("{:?}", status.as_str());
+    if !color {
+        return text;
+    }
+
+    let code = match status {
+        ZoomChannelStatus::On => 32,
+        ZoomChannelStatus::Off => 31,
+        ZoomChannelStatus::Starting | ZoomChannelStatus::Unknown => 33
+    };
+    format!("\x1b[{}m{}\x1b[0m", code, text)
+}
+
+/// Describe the time spent in each status as seconds and a percentage of the total
+fn describe_status_times(times: &StatusTimes, total: Duration) -> String {
+    let total_ms = total.num_milliseconds().max(1);
+    [("starting", times.starting), ("on", times.on), ("off", times.off), ("unknown", times.unknown)].iter()
+        .map(|(name, time)| format!("{} {:.1}s ({}%)", name, time.num_milliseconds() as f64 / 1000.0, time.num_milliseconds() * 100 / total_ms))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// Print how long each channel spent in each status over a profiling run
+fn print_profile(profile: &StatusProfile, total: Duration, reassignments: u64) {
+    println!("Profile over {}s:", total.num_seconds());
+    println!("  Call: {}", describe_status_times(&profile.call, total));
+    println!("  Video: {}", describe_status_times(&profile.video, total));
+    println!("  Audio: {}", describe_status_times(&profile.audio, total));
+    println!("  Reassignments: {}", reassignments);
+}
+
+/// Format an optional number as JSON, using `null` if it's missing
+fn json_option(value: Option<i64>) -> String {
+    match value {
+        Some(value) => value.to_string(),
+        None => "null".to_string()
+    }
+}
+
+/// Format a list of keys and (already JSON formatted) values as a JSON object
+fn json_object(fields: &[(&str, String)]) -> String {
+    let fields: Vec<String> = fields.iter().map(|(key, value)| format!("\"{}\":{}", key, value)).collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+/// Format the details of the session state Home Assistant shows as sensor attributes, as a JSON object
+#[cfg(feature = "hass")]
+fn hass_attributes_json(session_state: &ZoomSessionState) -> String {
+    let video_kbps = session_state.channels.video.and_then(|stream| stream.bitrate_kbps()).map(i64::from);
+
+    json_object(&[
+        ("schema_version", SCHEMA_VERSION.to_string()),
+        ("video", format!("\"{}\"", session_state.video.as_str())),
+        ("audio", format!("\"{}\"", session_state.audio.as_str())),
+        ("control", format!("\"{}\"", session_state.control.as_str())),
+        ("camera_live", session_state.camera_live.to_string()),
+        ("video_quality", session_state.video_quality.map_or("null".to_string(), |quality| format!("\"{}\"", quality.as_str()))),
+        ("video_kbps", json_option(video_kbps))
+    ])
+}
+
+/// The call, video, audio, control and screen share statuses, which output is only written for when one changes
+type Statuses = (ZoomChannelStatus, ZoomChannelStatus, ZoomChannelStatus, ZoomChannelStatus, ZoomChannelStatus);
+
+/// Pick out the statuses whose changes are worth writing output for
+fn statuses(session_state: &ZoomSessionState) -> Statuses {
+    (session_state.call, session_state.video, session_state.audio, session_state.control, session_state.screenshare)
+}
+
+/// Sends updates as JSON lines: one for each event, then the status
+///
+/// Like the text output, the status is only sent when it's changed or there are events, unless `verbose` is set, with
+/// an optional heartbeat so consumers can tell we're still running while nothing's happening.
+struct JsonLines<F: FnMut(&str)> {
+    write_line: F,
+    /// Include the raw statuses as `call_raw`, `video_raw`, `audio_raw` and `screenshare_raw`
+    show_raw: bool,
+    /// Send the status on every update, not just when it changes
+    verbose: bool,
+    /// Send the status again after this long without one, if at all
+    heartbeat: Option<Duration>,
+    last_statuses: Option<Statuses>,
+    last_sent: Instant
+}
+
+impl<F: FnMut(&str)> JsonLines<F> {
+    fn new(write_line: F, config: &Config) -> JsonLines<F> {
+        JsonLines {
+            write_line,
+            show_raw: config.show_raw,
+            verbose: config.verbose,
+            heartbeat: config.heartbeat_secs.map(|secs| Duration::seconds(secs.into())),
+            last_statuses: None,
+            last_sent: Instant::now()
+        }
+    }
+}
+
+impl<F: FnMut(&str)> StatusSink for JsonLines<F> {
+    fn publish(&mut self, state: &ZoomSessionState, events: &[SessionEvent]) {
+        let now = Instant::now();
+        let heartbeat_due = self.heartbeat.is_some_and(|heartbeat| elapsed_since(now, self.last_sent) >= heartbeat);
+        if !self.verbose && !heartbeat_due && events.is_empty() && self.last_statuses == Some(statuses(state)) {
+            return;
+        }
+
+        for event in events {
+            (self.write_line)(&event_json(event));
+        }
+        (self.write_line)(&status_json(state, self.show_raw));
+        self.last_statuses = Some(statuses(state));
+        self.last_sent = now;
+    }
+}
+
+/// Posts the call status to Home Assistant, only when something's changed rather than on every update
+#[cfg(feature = "hass")]
+struct HassSink {
+    client: hass::HassClient,
+    last_update: String
+}
+
+#[cfg(feature = "hass")]
+impl StatusSink for HassSink {
+    fn publish(&mut self, state: &ZoomSessionState, _events: &[SessionEvent]) {
+        let attributes = hass_attributes_json(state);
+        let update = format!("{}{}", state.call.as_str(), attributes);
+        if update != self.last_update {
+            self.client.update(state.call.as_str(), &attributes);
+            self.last_update = update;
+        }
+    }
+}
+
+/// Publishes each status to its own MQTT topic, only when it's changed rather than on every update
+#[cfg(feature = "mqtt")]
+struct MqttSink {
+    client: mqtt::MqttClient,
+    last_published: Vec<(&'static str, String)>
+}
+
+#[cfg(feature = "mqtt")]
+impl StatusSink for MqttSink {
+    fn publish(&mut self, state: &ZoomSessionState, _events: &[SessionEvent]) {
+        let statuses = vec![
+            ("call", state.call.as_str().to_string()),
+            ("video", state.video.as_str().to_string()),
+            ("audio", state.audio.as_str().to_string()),
+            ("camera_live", state.camera_live.to_string())
+        ];
+
+        for (topic, payload) in &statuses {
+            if !self.last_published.contains(&(topic, payload.clone())) {
+                self.client.publish(topic, payload);
+            }
+        }
+        self.last_published = statuses;
+    }
+}
+
+/// Keeps the metrics served to Prometheus up to date with every update
+#[cfg(feature = "metrics")]
+struct MetricsSink {
+    server: metrics::MetricsServer
+}
+
+#[cfg(feature = "metrics")]
+impl StatusSink for MetricsSink {
+    fn publish(&mut self, state: &ZoomSessionState, _events: &[SessionEvent]) {
+        self.server.update(metrics_text(state));
+    }
+}
+
+/// Format the session state as Prometheus metrics, in the text exposition format
+///
+/// Statuses are 1 when on and 0 when off, or NaN while they're still starting or unknown, so graphs show a gap rather
+/// than a misleading value.
+#[cfg(feature = "metrics")]
+fn metrics_text(session_state: &ZoomSessionState) -> String {
+    let gauge_value = |status: ZoomChannelStatus| match status {
+        ZoomChannelStatus::On => "1",
+        ZoomChannelStatus::Off => "0",
+        ZoomChannelStatus::Starting | ZoomChannelStatus::Unknown => "NaN"
+    };
+    let gauges = [
+        ("zoom_call_active", "Whether a Zoom call is in progress", session_state.call),
+        ("zoom_video_active", "Whether the Zoom video stream is sending", session_state.video),
+        ("zoom_audio_active", "Whether the Zoom audio stream is sending", session_state.audio)
+    ];
+
+    let mut text = String::new();
+    for (name, help, status) in &gauges {
+        text.push_str(&format!("# HELP {} {}\n# TYPE {} gauge\n{} {}\n", name, help, name, name, gauge_value(*status)));
+    }
+
+    text.push_str("# HELP zoom_average_packet_bytes Average packet size of each Zoom stream\n# TYPE zoom_average_packet_bytes gauge\n");
+    let channels = &session_state.channels;
+    for (channel, stream) in [("video", channels.video), ("audio", channels.audio), ("control", channels.control)] {
+        if let Some(stream) = stream {
+            text.push_str(&format!("zoom_average_packet_bytes{{channel=\"{}\"}} {}\n", channel, stream.average_packet_size()));
+        }
+    }
+
+    text
+}
+
+/// Format the count of ports in each size tier as a JSON object
+fn ports_by_tier_json(tiers: &PortTiers) -> String {
+    json_object(&[
+        ("video", tiers.video.to_string()),
+        ("audio", tiers.audio.to_string()),
+        ("control", tiers.control.to_string()),
+        ("none", tiers.none.to_string())
+    ])
+}
+
+/// Describe how many ports fell into each size tier
+fn describe_ports_seen(channels: &ZoomChannels) -> String {
+    let tiers = &channels.ports_by_tier;
+    format!("{} ports seen ({} video, {} audio, {} control, {} too small)", channels.ports_seen, tiers.video, tiers.audio,
+        tiers.control, tiers.none)
+}
+
+/// Format the session state as a single line JSON object
+///
+/// # Arguments
+/// * `session_state` - State to format
+/// * `show_raw` - Include the raw statuses as `call_raw`, `video_raw`, `audio_raw` and `screenshare_raw`
+fn status_json(session_state: &ZoomSessionState, show_raw: bool) -> String {
+    let [control_first_seen, audio_first_seen, video_first_seen] = session_state.first_seen_offsets().map(json_option);
+
+    let mut fields = vec![
+        ("schema_version", SCHEMA_VERSION.to_string()),
+        ("ts", format!("\"{}\"", Utc::now().to_rfc3339())),
+        ("mode", format!("\"{}\"", session_state.mode.as_str()))
+    ];
+
+    // Each status is followed by its raw value if asked for, so they're easy to compare
+    let statuses = [
+        ("call", session_state.call, Some(("call_raw", session_state.call_raw))),
+        ("video", session_state.video, Some(("video_raw", session_state.video_raw))),
+        ("audio", session_state.audio, Some(("audio_raw", session_state.audio_raw))),
+        ("control", session_state.control, None),
+        ("screenshare", session_state.screenshare, Some(("screenshare_raw", session_state.screenshare_raw)))
+    ];
+    for (name, status, raw) in statuses {
+        fields.push((name, format!("\"{}\"", status.as_str())));
+        if let Some((raw_name, raw_status)) = raw.filter(|_| show_raw) {
+            fields.push((raw_name, format!("\"{}\"", raw_status.as_str())));
+        }
+    }
+
+    fields.extend([
+        ("camera_live", session_state.camera_live.to_string()),
+        ("video_quality", session_state.video_quality.map_or("null".to_string(), |quality| format!("\"{}\"", quality.as_str()))),
+        ("activity_level", session_state.activity_level.to_string()),
+        ("in_meeting", session_state.is_in_meeting().to_string()),
+        ("camera_on", session_state.camera_on().to_string()),
+        ("mic_on", session_state.mic_on().to_string()),
+        ("mic_muted", session_state.mic_muted().to_string()),
+        ("camera_off", session_state.camera_off().to_string()),
+        ("connecting", session_state.connecting.to_string()),
+        ("undecodable_packets", session_state.channels.undecodable_packets.to_string()),
+        ("ports_seen", session_state.channels.ports_seen.to_string()),
+        ("ports_by_tier", ports_by_tier_json(&session_state.channels.ports_by_tier)),
+        ("first_seen_ms", json_object(&[
+            ("control", control_first_seen),
+            ("audio", audio_first_seen),
+            ("video", video_first_seen)
+        ]))
+    ]);
+
+    json_object(&fields)
 }
 
 fn main() {
-    let capture_device = parse_args();
+    let config = parse_args();
+    let mut capture_options = config.capture_options.clone();
 
-    println!("Got device {:?}", capture_device);
+    let capture_device = match &config.source {
+        CaptureSource::Live(capture_device) => capture_device.clone(),
+        CaptureSource::Replay(files) => {
+            for file in files {
+                print_replay_summary(file, &capture_options, &config);
+            }
+            return;
+        }
+    };
 
-    let channel_status = Arc::new(RwLock::new(zoom_channels::ZoomChannels {
-        video: None,
-        audio: None,
-        control: None
-    }));
+    if let Some(filter) = &config.filter_test {
+        run_filter_test(&capture_device, &capture_options, filter);
+    }
+
+    let mut sinks: Vec<Box<dyn StatusSink>> = Vec::new();
+
+    // --once prints a single status of its own at the end
+    if config.format == OutputFormat::Json && config.once_secs.is_none() {
+        sinks.push(Box::new(JsonLines::new(|line: &str| println!("{}", line), &config)));
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = &config.socket_path {
+        let server = socket_server::SocketServer::bind(path, config.socket_mode).unwrap_or_else(|e| {
+            eprintln!("Couldn't listen on socket {}: {}", path, e);
+            std::process::exit(1);
+        });
+        sinks.push(Box::new(JsonLines::new(move |line: &str| server.broadcast(line), &config)));
+    }
+
+    #[cfg(windows)]
+    if let Some(name) = &config.pipe_name {
+        let server = pipe_server::PipeServer::bind(name).unwrap_or_else(|e| {
+            eprintln!("Couldn't serve pipe {}: {}", name, e);
+            std::process::exit(1);
+        });
+        sinks.push(Box::new(JsonLines::new(move |line: &str| server.broadcast(line), &config)));
+    }
+
+    #[cfg(feature = "hass")]
+    if let Some((url, token)) = &config.hass {
+        let client = hass::HassClient::new(url, token).unwrap_or_else(|e| {
+            eprintln!("Invalid --hass-url: {}", e);
+            std::process::exit(2);
+        });
+        sinks.push(Box::new(HassSink { client, last_update: String::new() }));
+    }
+
+    #[cfg(feature = "mqtt")]
+    if let Some((broker, topic_prefix)) = &config.mqtt {
+        let client = mqtt::MqttClient::new(broker, topic_prefix).unwrap_or_else(|e| {
+            eprintln!("Invalid --mqtt-broker: {}", e);
+            std::process::exit(2);
+        });
+        sinks.push(Box::new(MqttSink { client, last_published: Vec::new() }));
+    }
+
+    #[cfg(feature = "metrics")]
+    if let Some(address) = &config.metrics_address {
+        let server = metrics::MetricsServer::bind(address).unwrap_or_else(|e| {
+            eprintln!("Couldn't serve metrics on {}: {}", address, e);
+            std::process::exit(1);
+        });
+        sinks.push(Box::new(MetricsSink { server }));
+    }
 
-    let mut packet_thread = stoppable_thread::spawn(enclose!((capture_device, channel_status) move |stopped| {
-        stream_analyser::PortDiscoveryCapture::run(capture_device, channel_status, stopped)
+    // When tracing, stdout is reserved for the CSV so it can be redirected to a file, a monitoring plugin and --once
+    // only print their result, and the stream table and JSON replace the statuses
+    let print_status = !capture_options.trace_csv && !config.nagios && config.once_secs.is_none() && !config.stream_table
+        && config.format == OutputFormat::Text;
+
+    if capture_options.trace_csv {
+        println!("timestamp,port,length,avg_size,class");
+    } else if print_status {
+        println!("Got device {:?}", capture_device);
+        if config.show_addresses {
+            print_addresses(&capture_device);
+        }
+        println!("Classifying with {}", capture_options.describe_classification());
+    }
+
+    if config.self_test {
+        run_self_test(&capture_device, &capture_options);
+    }
+
+    let channel_status = Arc::new(RwLock::new(ZoomChannels::new()));
+    let mut session_state = new_session_state(&config);
+
+    let mut packet_thread = stoppable_thread::spawn(enclose!((capture_device, capture_options, channel_status) move |stopped| {
+        stream_analyser::PortDiscoveryCapture::run(capture_device, capture_options, channel_status, stopped)
+            .unwrap_or_else(|e| exit_with_error(e))
     }));
 
-    let mut discover_mode = true;
-    let state_change_interval = Duration::milliseconds(200);
+    #[cfg(any(unix, windows))]
+    handle_interrupt();
+    let mut summary = SessionSummary::new();
+    let mut call_summary = SessionSummary::new();
+    let mut profile = StatusProfile::new();
+    let started_at = Instant::now();
+    let mut last_update = started_at;
+
+    let mut last_heartbeat = Instant::now();
+    let mut last_stream_table: Option<Instant> = None;
+    let mut mode_since = Instant::now();
+    let mut rediscovering = false;
+    let mut no_packets_hinted = capture_options.promisc;
+    #[cfg(all(target_os = "linux", feature = "identify-process"))]
+    let mut identified_ports = std::collections::HashSet::new();
+    #[cfg(all(target_os = "macos", feature = "macos-focus"))]
+    let mut was_in_meeting = session_state.is_in_meeting();
+    #[cfg(all(any(target_os = "linux", target_os = "macos"), feature = "notify"))]
+    let mut notified_call_started: Option<Instant> = None;
+
+    let mut last_printed_statuses = None;
 
     loop {
-        println!("Current streams known {:?}", channel_status);
-
-        let now = Utc::now();
-
-        let (video_status, audio_status) = {
-            let channel_status_read = channel_status.read().unwrap();
-            let video_status = match &channel_status_read.video {
-                Some(stream) => {
-                    if now - stream.last_packet_seen > state_change_interval {
-                        "off"
-                    } else {
-                        "on"
+        if print_status && config.verbose {
+            println!("Current streams known {:?}", channel_status);
+        }
+
+        // Timing is monotonic, so clock steps and sleeping don't upset the statuses
+        let now = Instant::now();
+
+        let previous_state = session_state.clone();
+        session_state.update_channels(&channel_status.read().unwrap(), now);
+        let events = session_state.events_since(&previous_state);
+        summary.record(&previous_state, elapsed_since(now, last_update), &events);
+        if events.contains(&SessionEvent::CallStarted) {
+            call_summary = SessionSummary::new();
+        }
+        call_summary.record(&previous_state, elapsed_since(now, last_update), &events);
+        if config.summary && print_status && events.contains(&SessionEvent::CallEnded) {
+            println!("Call ended after {}s: {}", call_summary.call_time.num_seconds(), describe_summary(&call_summary));
+        }
+        profile.record(&previous_state, elapsed_since(now, last_update));
+        last_update = now;
+
+        // Carry calibrated thresholds over to any later captures, and stop waiting on calibration to monitor
+        if capture_options.calibrate && session_state.channels.calibration_done {
+            capture_options.calibrate = false;
+            match &session_state.channels.calibrated_buckets {
+                Some(buckets) => {
+                    capture_options.size_buckets = buckets.clone();
+                    if print_status {
+                        println!("Calibrated, now classifying with {}", capture_options.describe_classification());
                     }
+                },
+                None => eprintln!("Couldn't calibrate, as there weren't enough different streams to tell apart - keeping {}",
+                    capture_options.describe_classification())
+            }
+        }
+
+        // Only print when a status changes, so running as a service doesn't flood the logs
+        let statuses = Some(statuses(&session_state));
+        if print_status && (config.verbose || statuses != last_printed_statuses || !events.is_empty()) {
+            last_printed_statuses = statuses;
+            println!("Statuses: Mode: {:?} Call: {} Video: {} Audio: {} Control: {} Screen share: {} Mic muted: {:?} Camera off: {:?} Camera live: {:?} Video quality: {:?} Activity: {} Connecting: {:?}",
+                session_state.mode.as_str(), paint_status(session_state.call, config.color),
+                paint_status(session_state.video, config.color), paint_status(session_state.audio, config.color),
+                paint_status(session_state.control, config.color), paint_status(session_state.screenshare, config.color),
+                session_state.mic_muted(), session_state.camera_off(),
+                session_state.camera_live, session_state.video_quality.map(|quality| quality.as_str()), session_state.activity_level,
+                session_state.connecting);
+            if config.show_raw {
+                println!("Raw statuses: Call: {} Video: {} Audio: {} Screen share: {}",
+                    paint_status(session_state.call_raw, config.color), paint_status(session_state.video_raw, config.color),
+                    paint_status(session_state.audio_raw, config.color),
+                    paint_status(session_state.screenshare_raw, config.color));
+            }
+            if config.verbose {
+                let first_seen = session_state.first_seen_offsets();
+                println!("First seen (ms after call start): Control: {:?} Audio: {:?} Video: {:?}", first_seen[0], first_seen[1], first_seen[2]);
+            }
+
+            for event in &events {
+                match event {
+                    SessionEvent::VideoBitrateChanged { from_kbps, to_kbps } =>
+                        println!("Event: {} from {}kbps to {}kbps", event.as_str(), from_kbps, to_kbps),
+                    _ => println!("Event: {}", event.as_str())
                 }
-                None => "unknown"
-            };
-            let audio_status = match &channel_status_read.audio {
-                Some(stream) => {
-                    if now - stream.last_packet_seen > state_change_interval {
-                        "off"
-                    } else {
-                        "on"
+            }
+        }
+
+        for sink in sinks.iter_mut() {
+            sink.publish(&session_state, &events);
+        }
+
+        #[cfg(all(target_os = "linux", feature = "identify-process"))]
+        if config.identify_process && print_status {
+            let channels = &session_state.channels;
+            let streams = [("video", channels.video), ("audio", channels.audio), ("control", channels.control)];
+            for (kind, stream) in streams.iter().filter_map(|(kind, stream)| stream.map(|stream| (kind, stream))) {
+                // Each port only needs looking up once, as searching every process' file descriptors is slow
+                if identified_ports.insert(stream.source_port()) {
+                    match process_lookup::process_for_port(stream.source_port()) {
+                        Some(process) => println!("Port {} ({}) belongs to {} (pid {})", stream.source_port(), kind, process.name, process.pid),
+                        None => println!("Port {} ({}) belongs to an unknown process", stream.source_port(), kind)
                     }
                 }
-                None => "unknown"
-            };
-            (video_status, audio_status)
-        };
+            }
+        }
+
+        #[cfg(all(target_os = "macos", feature = "macos-focus"))]
+        {
+            if let Some(shortcut) = &config.focus_shortcut {
+                if session_state.is_in_meeting() != was_in_meeting {
+                    macos_focus::run_focus_shortcut(shortcut, session_state.is_in_meeting());
+                }
+            }
+            was_in_meeting = session_state.is_in_meeting();
+        }
 
-        println!("Statuses: Video: {:?} Audio: {:?}", video_status, audio_status);
+        #[cfg(all(any(target_os = "linux", target_os = "macos"), feature = "notify"))]
+        if let Some((start_text, end_text)) = &config.notify {
+            for event in &events {
+                match event {
+                    SessionEvent::CallStarted => {
+                        notify::show_notification(start_text);
+                        notified_call_started = Some(now);
+                    },
+                    SessionEvent::CallEnded => {
+                        let duration = notified_call_started.take().map_or("an unknown time".to_string(), |started| {
+                            let duration = elapsed_since(now, started);
+                            format!("{}m {}s", duration.num_minutes(), duration.num_seconds() % 60)
+                        });
+                        notify::show_notification(&end_text.replace("{duration}", &duration));
+                    },
+                    _ => ()
+                }
+            }
+        }
 
-        if video_status != "unknown" && audio_status != "unknown" && discover_mode {
-            println!("Both channels have a status, switching to monitor mode");
+        if !no_packets_hinted && session_state.channels.packets_processed == 0
+            && elapsed_since(now, started_at) >= Duration::seconds(NO_PACKETS_HINT_SECS) {
+            eprintln!("No Zoom packets seen after {}s - if you're in a call and using a VM or bridged network interface, try --promisc",
+                NO_PACKETS_HINT_SECS);
+            no_packets_hinted = true;
+        }
+
+        if config.stream_table && last_stream_table.is_none_or(|last| {
+            elapsed_since(now, last) >= Duration::milliseconds(STREAM_TABLE_REFRESH_MSEC)
+        }) {
+            print_stream_table(&session_state.channels, now, std::io::stdout().is_terminal());
+            last_stream_table = Some(now);
+        }
+
+        if let Some(interval) = config.heartbeat_secs {
+            if print_status && elapsed_since(now, last_heartbeat) >= Duration::seconds(interval.into()) {
+                let channels = &session_state.channels;
+                println!("Heartbeat: capture last active {}ms ago, {} packets processed, {} undecodable, {}",
+                    elapsed_since(now, channels.last_capture_cycle).num_milliseconds(), channels.packets_processed, channels.undecodable_packets,
+                    describe_ports_seen(channels));
+                last_heartbeat = now;
+            }
+        }
+
+        // Going back to discovery from monitor mode gives it a while to find new ports, even though the channels are
+        // already settled. Monitoring is only worth it during a call, as the next one may well use new ports.
+        let discovered_long_enough = !rediscovering || elapsed_since(now, mode_since) >= Duration::seconds(REDISCOVER_SECS);
+        let call_timeout = Duration::milliseconds(session_state.call_timeout_ms);
+        // Calibration needs discovery's view of every stream, so monitoring waits until it's finished
+        if session_state.ready_to_monitor(now) && !config.no_monitor && discovered_long_enough && !capture_options.calibrate
+            && forget_idle_streams(&channel_status, now, call_timeout) {
+            if print_status && !rediscovering {
+                println!("Channels found, switching to monitor mode");
+            }
+            packet_thread.stop().join().unwrap();
+            session_state.mode = CaptureMode::Monitor;
+            mode_since = now;
+            rediscovering = false;
+
+            packet_thread = stoppable_thread::spawn(enclose!((capture_device, capture_options, channel_status) move |stopped| {
+                stream_analyser::PortMonitorCapture::run(capture_device, capture_options, channel_status, stopped)
+                    .unwrap_or_else(|e| exit_with_error(e))
+            }));
+        } else if session_state.mode == CaptureMode::Monitor && (!session_state.is_in_meeting()
+            || elapsed_since(now, mode_since) >= Duration::seconds(MONITOR_REDISCOVER_SECS)) {
+            // Monitor mode only captures the ports it already knows, so look again for any that have appeared mid-call,
+            // or for the next call once this one's over
             packet_thread.stop().join().unwrap();
-            discover_mode = false;
+            if print_status && !session_state.is_in_meeting() {
+                println!("Call ended, switching to discover mode");
+            }
+            rediscovering = session_state.is_in_meeting();
+            session_state.mode = CaptureMode::Discover;
+            mode_since = now;
 
-            packet_thread = stoppable_thread::spawn(enclose!((capture_device, channel_status) move |stopped| {
-                stream_analyser::PortMonitorCapture::run(capture_device, channel_status, stopped)
+            packet_thread = stoppable_thread::spawn(enclose!((capture_device, capture_options, channel_status) move |stopped| {
+                stream_analyser::PortDiscoveryCapture::run(capture_device, capture_options, channel_status, stopped)
+                    .unwrap_or_else(|e| exit_with_error(e))
             }));
         }
 
+        if let Some(profile_secs) = config.profile_secs {
+            if elapsed_since(now, started_at) >= Duration::seconds(profile_secs.into()) {
+                print_profile(&profile, elapsed_since(now, started_at), session_state.channels.reassignments);
+                break;
+            }
+        }
+
+        if let Some(once_secs) = config.once_secs {
+            if elapsed_since(now, started_at) >= Duration::seconds(once_secs.into()) {
+                if !config.quiet {
+                    match config.format {
+                        OutputFormat::Text => println!("Call: {} Video: {} Audio: {}", session_state.call.as_str(),
+                            session_state.video.as_str(), session_state.audio.as_str()),
+                        OutputFormat::Json => println!("{}", status_json(&session_state, config.show_raw))
+                    }
+                }
+                std::process::exit(if session_state.is_in_meeting() { 0 } else { EXIT_NOT_IN_CALL });
+            }
+        }
+
+        if config.nagios && elapsed_since(now, started_at) >= Duration::seconds(NAGIOS_SETTLE_SECS) {
+            let (code, result) = nagios_result(&session_state, now);
+            println!("{}", result);
+            std::process::exit(code);
+        }
+
+        if STOP_REQUESTED.load(Ordering::SeqCst) {
+            if config.summary {
+                print_summary(&summary);
+            }
+            break;
+        }
+
         thread::sleep(std::time::Duration::from_millis(100));
 
     }
+
+    // Let the capture finish what it's doing and close the device, rather than dropping it mid-packet
+    packet_thread.stop().join().unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zoom_tally::zoom_channels::VideoQuality;
+
+    /// A JSON sink that collects the lines it writes
+    fn json_lines(lines: &mut Vec<String>, verbose: bool) -> JsonLines<impl FnMut(&str) + '_> {
+        JsonLines {
+            write_line: move |line: &str| lines.push(line.to_string()),
+            show_raw: false,
+            verbose,
+            heartbeat: None,
+            last_statuses: None,
+            last_sent: Instant::now()
+        }
+    }
+
+    /// Drop the timestamp from a JSON line, as it changes from run to run
+    fn without_ts(line: &str) -> String {
+        let ts_start = line.find(",\"ts\":\"").expect("no ts field");
+        let ts_end = line[ts_start + 7..].find('"').expect("ts not closed") + ts_start + 8;
+        format!("{}{}", &line[..ts_start], &line[ts_end..])
+    }
+
+    /// A session in a call with the camera on and mic off
+    fn in_call() -> ZoomSessionState {
+        let mut state = ZoomSessionState::new();
+        state.mode = CaptureMode::Monitor;
+        state.call = ZoomChannelStatus::On;
+        state.video = ZoomChannelStatus::On;
+        state.audio = ZoomChannelStatus::Off;
+        state.control = ZoomChannelStatus::Unknown;
+        state.screenshare = ZoomChannelStatus::Off;
+        state.camera_live = true;
+        state.video_quality = Some(VideoQuality::Sd);
+        state.activity_level = 55;
+        state
+    }
+
+    #[test]
+    fn status_json_field_names_and_values() {
+        assert_eq!(without_ts(&status_json(&in_call(), false)), concat!(
+            "{\"schema_version\":9,\"mode\":\"monitor\",\"call\":\"on\",\"video\":\"on\",\"audio\":\"off\",",
+            "\"control\":\"unknown\",\"screenshare\":\"off\",\"camera_live\":true,\"video_quality\":\"sd\",",
+            "\"activity_level\":55,\"in_meeting\":true,\"camera_on\":true,\"mic_on\":false,\"mic_muted\":true,",
+            "\"camera_off\":false,\"connecting\":false,\"undecodable_packets\":0,\"ports_seen\":0,",
+            "\"ports_by_tier\":{\"video\":0,\"audio\":0,\"control\":0,\"none\":0},",
+            "\"first_seen_ms\":{\"control\":null,\"audio\":null,\"video\":null}}"
+        ));
+    }
+
+    #[test]
+    fn status_json_before_anything_is_known() {
+        let line = status_json(&ZoomSessionState::new(), false);
+
+        assert!(line.contains("\"call\":\"starting\",\"video\":\"starting\",\"audio\":\"starting\""));
+        assert!(line.contains("\"video_quality\":null"));
+        assert!(line.contains("\"in_meeting\":false"));
+    }
+
+    #[test]
+    fn status_json_puts_raw_statuses_next_to_each_status() {
+        let mut state = in_call();
+        state.call_raw = ZoomChannelStatus::Off;
+        state.video_raw = ZoomChannelStatus::Off;
+        state.audio_raw = ZoomChannelStatus::Off;
+        state.screenshare_raw = ZoomChannelStatus::Unknown;
+
+        assert!(without_ts(&status_json(&state, true)).starts_with(concat!(
+            "{\"schema_version\":9,\"mode\":\"monitor\",\"call\":\"on\",\"call_raw\":\"off\",\"video\":\"on\",",
+            "\"video_raw\":\"off\",\"audio\":\"off\",\"audio_raw\":\"off\",\"control\":\"unknown\",",
+            "\"screenshare\":\"off\",\"screenshare_raw\":\"unknown\",\"camera_live\":true,"
+        )));
+    }
+
+    #[test]
+    fn event_json_field_names() {
+        assert_eq!(without_ts(&event_json(&SessionEvent::MicMuted)), "{\"schema_version\":9,\"event\":\"mic_muted\"}");
+        assert_eq!(without_ts(&event_json(&SessionEvent::VideoBitrateChanged { from_kbps: 1200, to_kbps: 400 })),
+            "{\"schema_version\":9,\"event\":\"video_bitrate_changed\",\"from_kbps\":1200,\"to_kbps\":400}");
+    }
+
+    #[test]
+    fn json_lines_only_sends_changes() {
+        let mut lines = Vec::new();
+        let mut sink = json_lines(&mut lines, false);
+        let mut state = ZoomSessionState::new();
+
+        sink.publish(&state, &[]);
+        sink.publish(&state, &[]);
+        state.call = ZoomChannelStatus::On;
+        sink.publish(&state, &[SessionEvent::CallStarted]);
+        sink.publish(&state, &[]);
+        drop(sink);
+
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].contains("\"call\":\"starting\""));
+        assert!(lines[1].contains("\"event\":\"call_started\""));
+        assert!(lines[2].contains("\"call\":\"on\""));
+    }
+
+    #[test]
+    fn json_lines_sends_every_update_when_verbose() {
+        let mut lines = Vec::new();
+        let mut sink = json_lines(&mut lines, true);
+        let state = ZoomSessionState::new();
+
+        sink.publish(&state, &[]);
+        sink.publish(&state, &[]);
+        drop(sink);
+
+        assert_eq!(lines.len(), 2);
+    }
 }
@@ -0,0 +1,63 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// How long to wait on a slow scraper before giving up on it
+const METRICS_TIMEOUT_MSEC: u64 = 2000;
+
+/// Serves the latest metrics over HTTP at `/metrics`, in Prometheus' text exposition format
+///
+/// Scrapers are answered one at a time from a background thread, which is plenty for the odd scrape every few seconds.
+pub struct MetricsServer {
+    latest: Arc<Mutex<String>>
+}
+
+impl MetricsServer {
+    /// Start listening on the given address, e.g. `127.0.0.1:9185`
+    pub fn bind(address: &str) -> std::io::Result<MetricsServer> {
+        let listener = TcpListener::bind(address)?;
+        let latest = Arc::new(Mutex::new(String::new()));
+
+        let serve_latest = latest.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                if let Err(e) = answer_scrape(stream, &serve_latest) {
+                    eprintln!("Couldn't answer metrics request: {}", e);
+                }
+            }
+        });
+
+        Ok(MetricsServer { latest })
+    }
+
+    /// Replace the metrics served with a new set, already in the exposition format
+    pub fn update(&self, metrics: String) {
+        *self.latest.lock().unwrap() = metrics;
+    }
+}
+
+/// Read a request and answer it with the metrics, or a 404 for anything but `GET /metrics`
+fn answer_scrape(mut stream: TcpStream, latest: &Mutex<String>) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_millis(METRICS_TIMEOUT_MSEC)))?;
+    stream.set_write_timeout(Some(Duration::from_millis(METRICS_TIMEOUT_MSEC)))?;
+
+    // Only the request line matters, but the headers are read too so the client isn't cut off mid-request
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim().is_empty() {
+        header.clear();
+    }
+
+    let mut request = request_line.split_whitespace();
+    let (status, body) = match (request.next(), request.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", latest.lock().unwrap().clone()),
+        _ => ("404 Not Found", "Metrics are at /metrics\n".to_string())
+    };
+
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body)
+}
@@ -0,0 +1,296 @@
+#![cfg(feature = "mqtt")]
+
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+
+/// Port brokers listen on for plain MQTT
+const MQTT_DEFAULT_PORT: u16 = 1883;
+
+/// How often the broker expects to hear from us, after which it treats us as gone and sends the will
+const MQTT_KEEP_ALIVE_SECS: u16 = 60;
+
+/// How long to wait on the broker before giving up on a connection
+const MQTT_TIMEOUT_MSEC: u64 = 2000;
+
+/// Published (retained) under the topic prefix while we're connected, and by the broker as our will once we're not
+const MQTT_AVAILABILITY_TOPIC: &str = "availability";
+
+/// Publishes retained messages to an MQTT broker, using MQTT 3.1.1 at QoS 0
+///
+/// Messages are sent from a background thread so a slow or unreachable broker doesn't hold up status updates. The
+/// broker is pinged while nothing's changing, and if it doesn't answer or the connection drops it's made again, and
+/// the latest value of every topic is sent again, so retained messages stay current. A will marks us `offline` under
+/// `<prefix>/availability` if we go away without saying so. Only plain TCP is supported.
+pub struct MqttClient {
+    messages: Sender<(String, String)>
+}
+
+impl MqttClient {
+    /// Start a client for the broker at `broker`, e.g. `homeassistant.local` or `mqtt://10.0.0.2:1883`
+    ///
+    /// # Arguments
+    /// * `broker` - Host name or address of the broker, with an optional port
+    /// * `topic_prefix` - Prefix for every topic published to, e.g. `zoom-tally`
+    pub fn new(broker: &str, topic_prefix: &str) -> Result<MqttClient, String> {
+        let host = broker.strip_prefix("mqtt://").unwrap_or(broker).trim_end_matches('/');
+        if host.is_empty() || host.contains('/') {
+            return Err(format!("MQTT broker {:?} should just be the server, like homeassistant.local:1883", broker));
+        }
+        let address = if host.contains(':') { host.to_string() } else { format!("{}:{}", host, MQTT_DEFAULT_PORT) };
+        let topic_prefix = topic_prefix.trim_end_matches('/').to_string();
+        let client_id = format!("zoom-tally-{}", std::process::id());
+
+        let (messages, received_messages) = mpsc::channel::<(String, String)>();
+        thread::spawn(move || {
+            let will_topic = format!("{}/{}", topic_prefix, MQTT_AVAILABILITY_TOPIC);
+            let mut latest = BTreeMap::new();
+            let mut connection: Option<TcpStream> = None;
+
+            loop {
+                // Wake up often enough to keep the connection alive while nothing's changing
+                let message = match received_messages.recv_timeout(Duration::from_secs((MQTT_KEEP_ALIVE_SECS / 2).into())) {
+                    Ok(message) => Some(message),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => return
+                };
+
+                let result = match (&mut connection, message) {
+                    (Some(stream), Some((topic, payload))) => {
+                        latest.insert(topic.clone(), payload.clone());
+                        publish(stream, &format!("{}/{}", topic_prefix, topic), &payload)
+                    },
+                    (Some(stream), None) => ping(stream),
+                    (None, message) => {
+                        if let Some((topic, payload)) = message {
+                            latest.insert(topic, payload);
+                        }
+                        // Nothing to say until the first message, after which we try to stay connected
+                        if latest.is_empty() {
+                            continue;
+                        }
+                        connect(&address, &client_id, &will_topic).and_then(|mut stream| {
+                            publish(&mut stream, &will_topic, "online")?;
+                            for (topic, payload) in &latest {
+                                publish(&mut stream, &format!("{}/{}", topic_prefix, topic), payload)?;
+                            }
+                            connection = Some(stream);
+                            Ok(())
+                        })
+                    }
+                };
+
+                if let Err(e) = result {
+                    eprintln!("Couldn't publish to MQTT broker {}: {}", address, e);
+                    connection = None;
+                }
+            }
+        });
+
+        Ok(MqttClient { messages })
+    }
+
+    /// Queue a retained message to `<prefix>/<topic>`
+    pub fn publish(&self, topic: &str, payload: &str) {
+        let _ = self.messages.send((topic.to_string(), payload.to_string()));
+    }
+}
+
+/// Connect to the broker, with a retained `offline` will, and check it accepted us
+fn connect(address: &str, client_id: &str, will_topic: &str) -> std::io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(address)?;
+    stream.set_read_timeout(Some(Duration::from_millis(MQTT_TIMEOUT_MSEC)))?;
+    stream.set_write_timeout(Some(Duration::from_millis(MQTT_TIMEOUT_MSEC)))?;
+    stream.write_all(&connect_packet(client_id, will_topic))?;
+
+    // CONNACK is always 4 bytes, with the return code last
+    let mut connack = [0; 4];
+    stream.read_exact(&mut connack)?;
+    match connack {
+        [0x20, 0x02, _, 0x00] => Ok(stream),
+        [0x20, 0x02, _, code] => Err(std::io::Error::other(format!("broker refused the connection with code {}", code))),
+        _ => Err(std::io::Error::other("broker didn't answer like an MQTT broker"))
+    }
+}
+
+/// Check the broker's still there, reading whatever it's sent since the last ping so it doesn't pile up
+///
+/// We don't subscribe to anything, so the broker shouldn't send anything else, but anything it does is skipped. Fails
+/// if there's no PINGRESP before the stream's read timeout.
+fn ping<S: Read + Write>(stream: &mut S) -> std::io::Result<()> {
+    stream.write_all(&packet(0xC0, &[]))?;
+    loop {
+        match read_packet(stream)? {
+            (0xD0, body) if body.is_empty() => return Ok(()),
+            (0xD0, _) => return Err(std::io::Error::other("broker sent a malformed PINGRESP")),
+            _ => continue
+        }
+    }
+}
+
+/// Read a control packet from the broker, returning its type and flags byte and its body
+fn read_packet<R: Read>(stream: &mut R) -> std::io::Result<(u8, Vec<u8>)> {
+    let mut byte = [0; 1];
+    stream.read_exact(&mut byte)?;
+    let type_and_flags = byte[0];
+
+    // The remaining length is a variable length integer of at most four bytes, least significant first
+    let mut remaining = 0;
+    for shift in (0..28).step_by(7) {
+        stream.read_exact(&mut byte)?;
+        remaining |= usize::from(byte[0] & 0x7F) << shift;
+        if byte[0] & 0x80 == 0 {
+            let mut body = vec![0; remaining];
+            stream.read_exact(&mut body)?;
+            return Ok((type_and_flags, body));
+        }
+    }
+    Err(std::io::Error::other("broker sent a packet with an invalid remaining length"))
+}
+
+/// CONNECT packet for a clean session with a retained `offline` will
+fn connect_packet(client_id: &str, will_topic: &str) -> Vec<u8> {
+    // Protocol name and level 4 (3.1.1), then flags for a clean session with a retained QoS 0 will
+    let mut body = encode_string("MQTT");
+    body.extend_from_slice(&[0x04, 0x26]);
+    body.extend_from_slice(&MQTT_KEEP_ALIVE_SECS.to_be_bytes());
+    body.extend(encode_string(client_id));
+    body.extend(encode_string(will_topic));
+    body.extend(encode_string("offline"));
+    packet(0x10, &body)
+}
+
+/// Send a retained QoS 0 message
+fn publish(stream: &mut TcpStream, topic: &str, payload: &str) -> std::io::Result<()> {
+    stream.write_all(&publish_packet(topic, payload))
+}
+
+/// PUBLISH packet for a retained QoS 0 message
+fn publish_packet(topic: &str, payload: &str) -> Vec<u8> {
+    let mut body = encode_string(topic);
+    body.extend_from_slice(payload.as_bytes());
+    packet(0x31, &body)
+}
+
+/// Frame a control packet: its type and flags byte, the remaining length as a variable length integer, then the body
+fn packet(type_and_flags: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![type_and_flags];
+    let mut remaining = body.len();
+    loop {
+        let byte = (remaining % 128) as u8;
+        remaining /= 128;
+        if remaining > 0 {
+            packet.push(byte | 0x80);
+        } else {
+            packet.push(byte);
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+/// Encode a string as MQTT does, prefixed with its length as a 16 bit big endian number
+fn encode_string(s: &str) -> Vec<u8> {
+    let mut encoded = (s.len() as u16).to_be_bytes().to_vec();
+    encoded.extend_from_slice(s.as_bytes());
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remaining_length_uses_as_few_bytes_as_it_can() {
+        assert_eq!(packet(0xC0, &[]), vec![0xC0, 0x00]);
+        assert_eq!(packet(0x31, &[0; 127])[..2], [0x31, 0x7F]);
+        assert_eq!(packet(0x31, &[0; 128])[..3], [0x31, 0x80, 0x01]);
+        assert_eq!(packet(0x31, &[0; 16_383])[..3], [0x31, 0xFF, 0x7F]);
+        assert_eq!(packet(0x31, &[0; 16_384])[..4], [0x31, 0x80, 0x80, 0x01]);
+        assert_eq!(packet(0x31, &[0; 200]).len(), 203);
+    }
+
+    #[test]
+    fn strings_are_prefixed_with_their_length() {
+        assert_eq!(encode_string(""), vec![0x00, 0x00]);
+        assert_eq!(encode_string("MQTT"), vec![0x00, 0x04, b'M', b'Q', b'T', b'T']);
+    }
+
+    #[test]
+    fn publish_packet_is_retained_qos_0() {
+        assert_eq!(publish_packet("zt/video", "on"), vec![
+            0x31, 0x0C,
+            0x00, 0x08, b'z', b't', b'/', b'v', b'i', b'd', b'e', b'o',
+            b'o', b'n'
+        ]);
+    }
+
+    /// A connection to a broker that will send back `replies`, recording what's written to it
+    struct FakeBroker {
+        replies: std::io::Cursor<Vec<u8>>,
+        written: Vec<u8>
+    }
+
+    impl FakeBroker {
+        fn new(replies: &[u8]) -> FakeBroker {
+            FakeBroker { replies: std::io::Cursor::new(replies.to_vec()), written: Vec::new() }
+        }
+    }
+
+    impl Read for FakeBroker {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.replies.read(buf)
+        }
+    }
+
+    impl Write for FakeBroker {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.written.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn read_packet_decodes_the_remaining_length() {
+        let mut reply = packet(0x30, &[7; 200]);
+        reply.extend_from_slice(&[0xD0, 0x00]);
+        let mut stream = std::io::Cursor::new(reply);
+
+        assert_eq!(read_packet(&mut stream).unwrap(), (0x30, vec![7; 200]));
+        assert_eq!(read_packet(&mut stream).unwrap(), (0xD0, vec![]));
+        assert!(read_packet(&mut stream).is_err());
+        assert!(read_packet(&mut std::io::Cursor::new(vec![0x30, 0xFF, 0xFF, 0xFF, 0xFF, 0x01])).is_err());
+    }
+
+    #[test]
+    fn ping_waits_for_pingresp() {
+        let mut broker = FakeBroker::new(&[0xD0, 0x00]);
+        assert!(ping(&mut broker).is_ok());
+        assert_eq!(broker.written, vec![0xC0, 0x00]);
+
+        // Anything else the broker sends first is skipped
+        let mut broker = FakeBroker::new(&[0x31, 0x03, 0x00, 0x01, b't', 0xD0, 0x00]);
+        assert!(ping(&mut broker).is_ok());
+    }
+
+    #[test]
+    fn ping_fails_without_pingresp() {
+        assert!(ping(&mut FakeBroker::new(&[])).is_err());
+        assert!(ping(&mut FakeBroker::new(&[0x20, 0x02, 0x00, 0x00])).is_err());
+        assert!(ping(&mut FakeBroker::new(&[0xD0, 0x01, 0x00])).is_err());
+    }
+
+    #[test]
+    fn connect_packet_asks_for_a_clean_session_with_a_will() {
+        let mut expected = vec![0x10, 0x20, 0x00, 0x04, b'M', b'Q', b'T', b'T', 0x04, 0x26, 0x00, 0x3C];
+        expected.extend_from_slice(b"\x00\x02id\x00\x07zt/will\x00\x07offline");
+        assert_eq!(connect_packet("id", "zt/will"), expected);
+    }
+}
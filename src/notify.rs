@@ -0,0 +1,39 @@
+#![cfg(feature = "notify")]
+
+use std::process::Command;
+use std::thread;
+
+/// Title shown on every notification
+const NOTIFICATION_TITLE: &str = "Zoom Tally";
+
+/// Show a desktop notification, using `notify-send` on Linux and AppleScript on macOS
+///
+/// The command runs on its own thread so a slow notification daemon doesn't hold up status updates.
+pub fn show_notification(text: &str) {
+    let mut command = notification_command(text);
+
+    thread::spawn(move || {
+        match command.status() {
+            Ok(status) if !status.success() => eprintln!("Notification command failed: {}", status),
+            Ok(_) => (),
+            Err(e) => eprintln!("Couldn't show notification: {}", e)
+        }
+    });
+}
+
+#[cfg(target_os = "linux")]
+fn notification_command(text: &str) -> Command {
+    let mut command = Command::new("notify-send");
+    command.args([NOTIFICATION_TITLE, text]);
+    command
+}
+
+#[cfg(target_os = "macos")]
+fn notification_command(text: &str) -> Command {
+    // AppleScript strings are double quoted, with backslash escapes
+    let quote = |s: &str| format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""));
+
+    let mut command = Command::new("osascript");
+    command.args(["-e", &format!("display notification {} with title {}", quote(text), quote(NOTIFICATION_TITLE))]);
+    command
+}
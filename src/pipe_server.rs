@@ -0,0 +1,126 @@
+use std::ffi::{c_void, OsStr};
+use std::fs::File;
+use std::io::Write;
+use std::os::windows::ffi::OsStrExt;
+use std::os::windows::io::{AsRawHandle, FromRawHandle};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// How many lines can be queued for a slow client before giving up on it and dropping it
+const CLIENT_QUEUE_LINES: usize = 16;
+
+/// Size of each pipe instance's output buffer
+const PIPE_BUFFER_SIZE: u32 = 4096;
+
+// Just enough of the Win32 named pipe API to run a server, from winbase.h and winerror.h
+type Handle = *mut c_void;
+const INVALID_HANDLE_VALUE: Handle = -1isize as Handle;
+const PIPE_ACCESS_OUTBOUND: u32 = 0x0000_0002;
+const PIPE_TYPE_BYTE: u32 = 0x0000_0000;
+const PIPE_WAIT: u32 = 0x0000_0000;
+const PIPE_REJECT_REMOTE_CLIENTS: u32 = 0x0000_0008;
+const PIPE_UNLIMITED_INSTANCES: u32 = 255;
+const ERROR_PIPE_CONNECTED: i32 = 535;
+
+#[link(name = "kernel32")]
+extern "system" {
+    fn CreateNamedPipeW(name: *const u16, open_mode: u32, pipe_mode: u32, max_instances: u32, out_buffer_size: u32,
+                        in_buffer_size: u32, default_timeout: u32, security_attributes: *mut c_void) -> Handle;
+    fn ConnectNamedPipe(pipe: Handle, overlapped: *mut c_void) -> i32;
+}
+
+/// Serves a Windows named pipe and sends status lines to every connected client
+///
+/// Each client is written to from its own thread, so one that stops reading can't hold up the others or the status
+/// updates. Lines are only sent as the status changes, so each new client is sent the latest line as soon as it
+/// connects.
+pub struct PipeServer {
+    clients: Arc<Mutex<Vec<SyncSender<String>>>>,
+    latest: Arc<Mutex<Option<String>>>
+}
+
+impl PipeServer {
+    /// Start serving the pipe with the given name, e.g. `\\.\pipe\zoomtally`, accepting clients on a background thread
+    pub fn bind(name: &str) -> std::io::Result<PipeServer> {
+        let wide_name: Vec<u16> = OsStr::new(name).encode_wide().chain(Some(0)).collect();
+
+        // Create the first instance up front, so a bad name is reported straight away
+        let mut pipe = create_instance(&wide_name)?;
+        let clients = Arc::new(Mutex::new(Vec::new()));
+        let latest = Arc::new(Mutex::new(None));
+
+        let (accept_clients, accept_latest) = (clients.clone(), latest.clone());
+        thread::spawn(move || {
+            loop {
+                if connect_instance(&pipe) {
+                    let (lines, received_lines) = mpsc::sync_channel::<String>(CLIENT_QUEUE_LINES);
+                    thread::spawn(move || write_client(pipe, received_lines));
+
+                    // Hold the clients lock while catching up, so a broadcast can't slip in between
+                    let mut clients = accept_clients.lock().unwrap();
+                    if let Some(line) = &*accept_latest.lock().unwrap() {
+                        let _ = lines.try_send(line.clone());
+                    }
+                    clients.push(lines);
+                }
+
+                pipe = match create_instance(&wide_name) {
+                    Ok(pipe) => pipe,
+                    Err(e) => {
+                        eprintln!("Couldn't accept any more pipe clients: {}", e);
+                        return;
+                    }
+                };
+            }
+        });
+
+        Ok(PipeServer { clients, latest })
+    }
+
+    /// Send a single line to every connected client, dropping any that have gone away or fallen too far behind
+    pub fn broadcast(&self, line: &str) {
+        let mut clients = self.clients.lock().unwrap();
+        clients.retain(|client| client.try_send(line.to_string()).is_ok());
+        *self.latest.lock().unwrap() = Some(line.to_string());
+    }
+}
+
+/// Create a new instance of the pipe for the next client to connect to
+///
+/// The instance is handed back as a `File`, which writes to the client once it's connected and closes the instance
+/// when dropped.
+fn create_instance(wide_name: &[u16]) -> std::io::Result<File> {
+    // Safe as the name is NUL terminated, and no security attributes means the default ones
+    let pipe = unsafe {
+        CreateNamedPipeW(wide_name.as_ptr(), PIPE_ACCESS_OUTBOUND, PIPE_TYPE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+            PIPE_UNLIMITED_INSTANCES, PIPE_BUFFER_SIZE, 0, 0, std::ptr::null_mut())
+    };
+
+    if pipe == INVALID_HANDLE_VALUE {
+        Err(std::io::Error::last_os_error())
+    } else {
+        // Safe as the handle is a pipe instance nothing else owns
+        Ok(unsafe { File::from_raw_handle(pipe) })
+    }
+}
+
+/// Wait for a client to connect to a pipe instance
+///
+/// Returns false if the client went away again before the connection completed.
+fn connect_instance(pipe: &File) -> bool {
+    // Safe as the handle stays open for as long as the File is borrowed
+    let connected = unsafe { ConnectNamedPipe(pipe.as_raw_handle(), std::ptr::null_mut()) } != 0;
+
+    // A client that connects between the instance being created and us waiting for it counts as connected too
+    connected || std::io::Error::last_os_error().raw_os_error() == Some(ERROR_PIPE_CONNECTED)
+}
+
+/// Write queued lines to a client until it disconnects, or it's dropped by the server
+fn write_client(mut client: File, lines: mpsc::Receiver<String>) {
+    for line in lines {
+        if writeln!(client, "{}", line).is_err() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use std::fs;
+
+/// A process that owns a socket
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OwningProcess {
+    pub pid: u32,
+    pub name: String
+}
+
+/// Find the process with a UDP socket bound to a local port, if any
+///
+/// Looks the port up in `/proc/net/udp` and `/proc/net/udp6` to get the socket's inode, then searches each process'
+/// open file descriptors for it. This is best effort: processes belonging to other users can't be searched without
+/// root, so `None` may just mean we weren't allowed to look.
+pub fn process_for_port(port: u16) -> Option<OwningProcess> {
+    let inode = socket_inode(port)?;
+    let socket_link = format!("socket:[{}]", inode);
+
+    fs::read_dir("/proc").ok()?.flatten()
+        .filter_map(|entry| entry.file_name().to_str().and_then(|name| name.parse::<u32>().ok()))
+        .find(|pid| {
+            fs::read_dir(format!("/proc/{}/fd", pid)).is_ok_and(|fds| {
+                fds.flatten().any(|fd| fs::read_link(fd.path()).is_ok_and(|target| target.to_str() == Some(socket_link.as_str())))
+            })
+        })
+        .map(|pid| OwningProcess {
+            pid,
+            name: fs::read_to_string(format!("/proc/{}/comm", pid)).map_or("unknown".to_string(), |comm| comm.trim().to_string())
+        })
+}
+
+/// Inode of the UDP socket bound to a local port, from the kernel's socket tables
+fn socket_inode(port: u16) -> Option<u64> {
+    ["/proc/net/udp", "/proc/net/udp6"].iter()
+        .filter_map(|table| fs::read_to_string(table).ok())
+        .find_map(|table| {
+            // Columns are: sl local_address rem_address st tx_queue:rx_queue tr:tm->when retrnsmt uid timeout inode
+            table.lines().skip(1).find_map(|line| {
+                let columns: Vec<&str> = line.split_whitespace().collect();
+                let local_port = columns.get(1)?.rsplit(':').next()?;
+                if u16::from_str_radix(local_port, 16).ok()? == port {
+                    columns.get(9)?.parse().ok()
+                } else {
+                    None
+                }
+            })
+        })
+}
@@ -0,0 +1,9 @@
+use crate::zoom_channels::{SessionEvent, ZoomSessionState};
+
+/// Somewhere status updates are sent, e.g. stdout, a socket or a home automation system
+///
+/// Each sink is given every update, so it's up to the sink to skip ones where nothing it cares about has changed.
+pub trait StatusSink {
+    /// Send the latest state, along with the events that happened since the last update
+    fn publish(&mut self, state: &ZoomSessionState, events: &[SessionEvent]);
+}
@@ -0,0 +1,71 @@
+use std::io::Write;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// How long to wait on a slow client before giving up on a write and dropping it
+const CLIENT_WRITE_TIMEOUT_MSEC: u64 = 100;
+
+/// Listens on a Unix domain socket and sends status lines to every connected client
+///
+/// Lines are only sent as the status changes, so each new client is sent the latest line as soon as it connects.
+pub struct SocketServer {
+    clients: Arc<Mutex<Vec<UnixStream>>>,
+    latest: Arc<Mutex<Option<String>>>
+}
+
+impl SocketServer {
+    /// Start listening on the given path, accepting clients on a background thread
+    ///
+    /// A stale socket left at the path by a previous run is removed first, but anything else there is left alone and
+    /// reported as an error, as we're likely running as root.
+    ///
+    /// # Arguments
+    /// * `path` - Where to create the socket
+    /// * `mode` - Permissions to give the socket, e.g. `0o666` to let any user connect
+    pub fn bind<P: AsRef<Path>>(path: P, mode: u32) -> std::io::Result<SocketServer> {
+        let path = path.as_ref();
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+            Ok(_) => return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists,
+                "something other than a socket is already there")),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e)
+        }
+
+        let listener = UnixListener::bind(path)?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        let clients = Arc::new(Mutex::new(Vec::new()));
+        let latest = Arc::new(Mutex::new(None));
+
+        let (accept_clients, accept_latest) = (clients.clone(), latest.clone());
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                if stream.set_write_timeout(Some(Duration::from_millis(CLIENT_WRITE_TIMEOUT_MSEC))).is_err() {
+                    continue;
+                }
+                // Hold the clients lock while catching up, so a broadcast can't slip in between
+                let mut clients = accept_clients.lock().unwrap();
+                let caught_up = match &*accept_latest.lock().unwrap() {
+                    Some(line) => writeln!(stream, "{}", line).is_ok(),
+                    None => true
+                };
+                if caught_up {
+                    clients.push(stream);
+                }
+            }
+        });
+
+        Ok(SocketServer { clients, latest })
+    }
+
+    /// Send a single line to every connected client, dropping any that have gone away
+    pub fn broadcast(&self, line: &str) {
+        let mut clients = self.clients.lock().unwrap();
+        clients.retain_mut(|client| writeln!(client, "{}", line).is_ok());
+        *self.latest.lock().unwrap() = Some(line.to_string());
+    }
+}
@@ -1,91 +1,1269 @@
 use std::sync::{Arc, RwLock};
 use std::collections::HashMap;
+use std::str::FromStr;
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::Instant;
 
-use chrono::{DateTime, Utc};
-use pcap::{Capture, Active, Packet};
-use etherparse::{SlicedPacket,TransportSlice};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use pcap::{Capture, Active, Activated, Linktype, Offline, Packet};
+use etherparse::{InternetSlice, SlicedPacket, TransportSlice};
 use stoppable_thread::SimpleAtomicBool;
 
 use crate::zoom_channels;
 use crate::custom_device::CustomDevice;
+use crate::error::ZoomTallyError;
 
-/// Length of the moving average window used to calculate average packet size
-const BITRATE_WINDOW_SIZE: u16 = 10;
+/// How many packets a stream needs before its averages are trusted
+pub const BITRATE_WINDOW_SIZE: u16 = 10;
+
+/// Default weight each new packet gets in a stream's moving averages, equivalent to averaging over the window
+pub const DEFAULT_EMA_ALPHA: f64 = 1.0 / BITRATE_WINDOW_SIZE as f64;
 
 /// If the packet is smaller than average_size / DROP_FACTOR, it's a keep alive, ignore it
 const DROP_FACTOR: u16 = 5 ;
 
-/// A stream of packets larger than this many bytes is probably audio
-const AUDIO_ABOVE: u16 = 90;
+/// A stream of packets larger than this many bytes is probably audio
+const AUDIO_ABOVE: u16 = 90;
+
+/// A stream of packets larger than this many bytes is probably video
+const VIDEO_ABOVE: u16 = 500;
+
+/// The kinds of stream Zoom sends
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChannelKind {
+    Video,
+    Audio,
+    Control
+}
+
+impl ChannelKind {
+    /// Lowercase name of the kind, as used in output
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChannelKind::Video => "video",
+            ChannelKind::Audio => "audio",
+            ChannelKind::Control => "control"
+        }
+    }
+
+    /// Position of the kind when ordered by packet size, largest first
+    fn size_rank(&self) -> u8 {
+        match self {
+            ChannelKind::Video => 0,
+            ChannelKind::Audio => 1,
+            ChannelKind::Control => 2
+        }
+    }
+}
+
+impl FromStr for ChannelKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "video" => Ok(ChannelKind::Video),
+            "audio" => Ok(ChannelKind::Audio),
+            "control" => Ok(ChannelKind::Control),
+            _ => Err(format!("Unknown channel kind {:?}, expected video, audio or control", s))
+        }
+    }
+}
+
+/// What to do with packets much smaller than the stream's average, which are normally keepalives
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeepalivePolicy {
+    /// Leave them out of the average and last seen time
+    Drop,
+    /// Count them like any other packet
+    Include
+}
+
+/// A stream whose average packet size is more than `above` bytes is of this `kind`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SizeBucket {
+    pub above: u16,
+    pub kind: ChannelKind
+}
+
+impl SizeBucket {
+    /// The standard video, audio and control tiers
+    pub fn defaults() -> Vec<SizeBucket> {
+        vec![
+            SizeBucket {above: VIDEO_ABOVE, kind: ChannelKind::Video},
+            SizeBucket {above: AUDIO_ABOVE, kind: ChannelKind::Audio},
+            SizeBucket {above: 0, kind: ChannelKind::Control}
+        ]
+    }
+
+    /// Parse a comma separated list of buckets like `500:video,90:audio,0:control`
+    ///
+    /// The buckets are returned largest first, so the first one a stream is above is the one it belongs to. Each kind
+    /// can only appear once, and the thresholds must go video, then audio, then control, or classification makes no
+    /// sense.
+    pub fn parse_list(list: &str) -> Result<Vec<SizeBucket>, String> {
+        let buckets = list.split(',')
+            .map(|bucket| bucket.trim().parse())
+            .collect::<Result<Vec<SizeBucket>, String>>()?;
+        SizeBucket::sorted(buckets)
+    }
+
+    /// The default tiers, with the video and/or audio thresholds moved
+    pub fn with_thresholds(video_above: Option<u16>, audio_above: Option<u16>) -> Result<Vec<SizeBucket>, String> {
+        let buckets = SizeBucket::defaults().into_iter()
+            .map(|bucket| match bucket.kind {
+                ChannelKind::Video => SizeBucket {above: video_above.unwrap_or(bucket.above), ..bucket},
+                ChannelKind::Audio => SizeBucket {above: audio_above.unwrap_or(bucket.above), ..bucket},
+                ChannelKind::Control => bucket
+            })
+            .collect();
+        SizeBucket::sorted(buckets)
+    }
+
+    /// Put buckets largest first, checking they go video, then audio, then control
+    fn sorted(mut buckets: Vec<SizeBucket>) -> Result<Vec<SizeBucket>, String> {
+        buckets.sort_by_key(|bucket| std::cmp::Reverse(bucket.above));
+
+        for pair in buckets.windows(2) {
+            if pair[0].kind.size_rank() >= pair[1].kind.size_rank() {
+                return Err(format!("Size buckets must go video > audio > control, but {} is at {} and {} at {}",
+                    pair[1].kind.as_str(), pair[1].above, pair[0].kind.as_str(), pair[0].above));
+            }
+        }
+
+        Ok(buckets)
+    }
+
+    /// Derive video, audio and control thresholds from the average sizes of a call's streams
+    ///
+    /// The sizes are split into three clusters at the two biggest gaps between them, with each threshold halfway
+    /// across its gap. Returns `None` if there aren't three different sizes to split.
+    pub fn calibrate(average_sizes: &[u16]) -> Option<Vec<SizeBucket>> {
+        let mut sizes = average_sizes.to_vec();
+        sizes.sort_unstable();
+        sizes.dedup();
+        if sizes.len() < 3 {
+            return None;
+        }
+
+        // Each gap between neighbouring sizes, as its width and the size below it, widest first
+        let mut gaps: Vec<(u16, u16)> = sizes.windows(2).map(|pair| (pair[1] - pair[0], pair[0])).collect();
+        gaps.sort_by_key(|(width, _)| std::cmp::Reverse(*width));
+
+        let mut thresholds: Vec<u16> = gaps.iter().take(2).map(|(width, below)| below + width / 2).collect();
+        thresholds.sort_by_key(|threshold| std::cmp::Reverse(*threshold));
+
+        Some(vec![
+            SizeBucket {above: thresholds[0], kind: ChannelKind::Video},
+            SizeBucket {above: thresholds[1], kind: ChannelKind::Audio},
+            SizeBucket {above: 0, kind: ChannelKind::Control}
+        ])
+    }
+}
+
+impl FromStr for SizeBucket {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (above, kind) = s.split_once(':').ok_or(format!("Size bucket {:?} should look like 500:video", s))?;
+        let above = above.parse().map_err(|_| format!("Size bucket threshold {:?} isn't a number of bytes", above))?;
+
+        Ok(SizeBucket {above, kind: kind.parse()?})
+    }
+}
+
+/// With `wait_for_device`, how long to wait before the first retry, doubling after each failure up to the maximum
+const WAIT_FOR_DEVICE_FIRST_RETRY_MSEC: i64 = 500;
+const WAIT_FOR_DEVICE_MAX_RETRY_MSEC: i64 = 30_000;
+
+/// How long calibration watches a call's streams before deriving thresholds from them
+const CALIBRATION_SECS: i64 = 5;
+
+/// How long calibration keeps trying to find enough different streams to tell apart before giving up
+const CALIBRATION_GIVE_UP_SECS: i64 = 30;
+
+/// In power save mode, how long without any packets before the capture is considered idle
+const POWER_SAVE_IDLE_AFTER_SECS: i64 = 30;
+
+/// In power save mode, how long pcap waits for packets while the capture is idle
+const POWER_SAVE_TIMEOUT_MSEC: i32 = 1000;
+
+/// Once a port is classified as control, another port can only take over after it's been quiet this long
+const CONTROL_STICKY_MSEC: i64 = 1000;
+
+/// Two video sized streams that have both sent within this long of each other are taken to be video and a screen share
+const SCREENSHARE_OVERLAP_MSEC: i64 = 2000;
+
+/// During the reclassification cooldown, a stream's average must be this far (as a percentage of the threshold)
+/// past the threshold between its old and new kind to be moved anyway
+const RECLASSIFY_MARGIN_PERCENT: u32 = 50;
+
+/// A video stream sending faster than this many kilobits per second is carrying camera frames, rather than the
+/// low-rate placeholder Zoom keeps alive while the camera is off
+pub const CAMERA_LIVE_ABOVE_KBPS: u32 = 200;
+
+/// Port Zoom's servers listen on for media and control streams
+const ZOOM_PORT: u16 = 8801;
+
+/// Default for how long pcap waits for packets before returning control to the capture loop
+const DEFAULT_CAPTURE_TIMEOUT_MSEC: i32 = 100;
+
+/// An inclusive range of destination ports Zoom traffic is sent to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16
+}
+
+impl PortRange {
+    /// BPF filter matching packets sent to a port in this range
+    fn destination_filter(&self) -> String {
+        if self.start == self.end {
+            format!("dst port {}", self.start)
+        } else {
+            format!("dst portrange {}-{}", self.start, self.end)
+        }
+    }
+
+    /// Parse a comma separated list of ports and ranges like `8801,8802` or `8801-8810,9000`
+    pub fn parse_list(list: &str) -> Result<Vec<PortRange>, String> {
+        list.split(',').map(|range| range.trim().parse()).collect()
+    }
+}
+
+/// BPF filter matching packets sent to any of the given ports
+fn destination_filter(ports: &[PortRange]) -> String {
+    let filters: Vec<String> = ports.iter().map(PortRange::destination_filter).collect();
+    if filters.len() == 1 {
+        filters.join("")
+    } else {
+        format!("({})", filters.join(" || "))
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    /// Parse a range like `8801-8810`, or a single port like `8801`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s.split_once('-').unwrap_or((s, s));
+        let parse_port = |port: &str| match port.trim().parse::<u16>() {
+            Ok(port) if port > 0 => Ok(port),
+            _ => Err(format!("{:?} isn't a valid port number", port))
+        };
+        let (start, end) = (parse_port(start)?, parse_port(end)?);
+
+        if start > end {
+            return Err(format!("Port range {}-{} ends before it starts", start, end));
+        }
+
+        Ok(PortRange {start, end})
+    }
+}
+
+/// IP version to restrict capture to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpFamily {
+    V4,
+    V6
+}
+
+impl IpFamily {
+    /// BPF primitive matching packets of this IP version
+    fn filter(&self) -> &'static str {
+        match self {
+            IpFamily::V4 => "ip",
+            IpFamily::V6 => "ip6"
+        }
+    }
+}
+
+/// Options controlling how packets are captured and reported
+#[derive(Debug, Clone)]
+pub struct CaptureOptions {
+    /// Print a CSV line to stdout for every packet processed, for offline analysis
+    pub trace_csv: bool,
+    /// How long pcap waits for packets before returning control to the capture loop
+    ///
+    /// Very low values wake the CPU more often, very high values delay noticing that a silent link has gone quiet.
+    pub capture_timeout_ms: i32,
+    /// Size of the pcap capture buffer in bytes, or `None` to use pcap's default
+    ///
+    /// Raise this if packets are being dropped on a busy link.
+    pub buffer_size: Option<i32>,
+    /// Only capture packets sent from the capture device's own IP addresses, ignoring other machines' traffic
+    pub own_only: bool,
+    /// Addresses to treat as our own for `own_only`, instead of the capture device's, e.g. to include a VPN address
+    /// assigned to another interface. Empty to use the device's.
+    pub local_addresses: Vec<IpAddr>,
+    /// Packet size tiers used to decide which kind of stream a port is carrying, largest first
+    pub size_buckets: Vec<SizeBucket>,
+    /// Destination ports Zoom traffic is sent to
+    pub zoom_ports: Vec<PortRange>,
+    /// Bytes of each packet to capture, or `None` to work out a safe length from the link type
+    pub snaplen: Option<i32>,
+    /// Print a line to stderr whenever a port moves from one kind of channel to another
+    pub log_reassignments: bool,
+    /// Only capture this IP version, or `None` to accept both
+    ///
+    /// On dual-stack networks Zoom may send over both at once, in which case the streams from both server endpoints
+    /// are classified together and can be confused. Picking one keeps detection deterministic.
+    pub ip_family: Option<IpFamily>,
+    /// How often to log the capture's received/dropped packet counts to stderr, if at all
+    pub stats_interval_secs: Option<u32>,
+    /// How long after a port is classified before it can be moved to another channel, unless its average moves well
+    /// past the threshold. Zero allows reclassifying at any time.
+    pub reclassify_cooldown_ms: u32,
+    /// Lengthen the capture timeout while no Zoom traffic is flowing, so an idle laptop wakes up less often
+    pub power_save: bool,
+    /// Whether small keepalive packets are left out of each stream's average
+    pub keepalive_policy: KeepalivePolicy,
+    /// Put the device in promiscuous mode, for bridged or virtual NICs that only pass the host's own traffic up that way
+    pub promisc: bool,
+    /// Whether the app has a separate control channel to look for. Without one, keepalives are part of the audio
+    /// stream and small streams are left unclassified rather than invented as a control channel.
+    pub control_channel: bool,
+    /// Tell streams apart by their source port and destination address, rather than just the source port, for when
+    /// the same port sends to several servers
+    pub key_by_destination: bool,
+    /// Once a call's found, watch its streams for a few seconds and replace the size buckets with thresholds derived
+    /// from them
+    pub calibrate: bool,
+    /// Keep retrying with backoff if the device doesn't exist or can't be opened yet, rather than giving up
+    pub wait_for_device: bool,
+    /// Weight each new packet gets in a stream's moving averages, between 0 and 1. Higher reacts faster to changes,
+    /// lower rides out bursts better.
+    pub ema_alpha: f64
+}
+
+impl CaptureOptions {
+    /// Describe the classification settings in effect, e.g. `video>500B, audio>90B, control>0B averaged with alpha 0.1
+    /// to dst port 8801`
+    pub fn describe_classification(&self) -> String {
+        let buckets: Vec<String> = self.size_buckets.iter()
+            .map(|bucket| format!("{}>{}B", bucket.kind.as_str(), bucket.above))
+            .collect();
+
+        format!("{} averaged with alpha {} to {}", buckets.join(", "), self.ema_alpha, destination_filter(&self.zoom_ports))
+    }
+
+    /// Addresses to only capture packets from, or `None` to capture from anyone
+    pub fn own_addresses<'a>(&'a self, capture_device: &'a CustomDevice) -> Option<&'a [IpAddr]> {
+        if !self.own_only {
+            None
+        } else if !self.local_addresses.is_empty() {
+            Some(&self.local_addresses)
+        } else {
+            Some(capture_device.addresses())
+        }
+    }
+}
+
+impl Default for CaptureOptions {
+    fn default() -> Self {
+        CaptureOptions {
+            trace_csv: false,
+            capture_timeout_ms: DEFAULT_CAPTURE_TIMEOUT_MSEC,
+            buffer_size: None,
+            own_only: false,
+            local_addresses: Vec::new(),
+            size_buckets: SizeBucket::defaults(),
+            zoom_ports: vec![PortRange {start: ZOOM_PORT, end: ZOOM_PORT}],
+            snaplen: None,
+            log_reassignments: false,
+            ip_family: None,
+            stats_interval_secs: None,
+            reclassify_cooldown_ms: 0,
+            power_save: false,
+            keepalive_policy: KeepalivePolicy::Drop,
+            promisc: false,
+            control_channel: true,
+            key_by_destination: false,
+            calibrate: false,
+            wait_for_device: false,
+            ema_alpha: DEFAULT_EMA_ALPHA
+        }
+    }
+}
+
+/// What streams are told apart by: the source port, and the destination address if the options ask for it
+pub type StreamKey = (u16, Option<IpAddr>);
+
+/// When a packet arrived, by the wall clock and by the monotonic clock
+///
+/// The wall clock time comes from pcap and is what gets reported, but it can jump when NTP steps the clock or the
+/// machine sleeps, so working out how long ago something happened uses the monotonic time instead.
+#[derive(Hash, Eq, PartialEq, Debug, Clone, Copy)]
+pub struct PacketTime {
+    /// When pcap captured the packet
+    pub captured_at: DateTime<Utc>,
+    /// When the packet was read from the capture
+    pub seen_at: Instant
+}
+
+/// How long it's been between two monotonic times, or zero if `earlier` is actually later
+pub fn elapsed_since(now: Instant, earlier: Instant) -> Duration {
+    Duration::from_std(now.saturating_duration_since(earlier)).unwrap_or_else(|_| Duration::max_value())
+}
+
+/// A single port sending a stream of packets to a remote server
+#[derive(Hash, Eq, PartialEq, Debug, Clone, Copy)]
+pub struct PacketStream {
+    source_port: u16,
+    /// Address the stream is sent to, if streams are being told apart by destination
+    destination: Option<IpAddr>,
+    average_packet_size: u16,
+    last_packet_seen: PacketTime,
+    /// When the first packet from this port was seen
+    first_seen: PacketTime,
+    window_size: u16,
+    /// Moving average of the time between packets, in microseconds
+    average_interval_us: u32,
+    /// When the stream was last put into a different channel
+    last_classified_at: Option<Instant>
+}
+
+impl PacketStream {
+    fn new(source_port: u16, destination: Option<IpAddr>, time: PacketTime) -> PacketStream {
+        PacketStream {
+            source_port,
+            destination,
+            average_packet_size: 0,
+            last_packet_seen: time,
+            first_seen: time,
+            window_size: 0,
+            average_interval_us: 0,
+            last_classified_at: None
+        }
+    }
+
+    /// Port the stream is sent from
+    pub fn source_port(&self) -> u16 {
+        self.source_port
+    }
+
+    /// Address the stream is sent to, if streams are being told apart by destination
+    pub fn destination(&self) -> Option<IpAddr> {
+        self.destination
+    }
+
+    /// What the stream is told apart from others by
+    pub fn key(&self) -> StreamKey {
+        (self.source_port, self.destination)
+    }
+
+    /// True if both are the same stream, even if they've seen different packets
+    pub fn is_same_stream(&self, other: &PacketStream) -> bool {
+        self.source_port == other.source_port && self.destination == other.destination
+    }
+
+    /// True if a packet from a port to an address belongs to this stream
+    fn matches(&self, source_port: u16, destination: IpAddr) -> bool {
+        self.source_port == source_port && self.destination.is_none_or(|stream_destination| stream_destination == destination)
+    }
+
+    /// BPF filter matching the stream's packets
+    fn filter(&self) -> String {
+        match self.destination {
+            Some(destination) => format!("(src port {} && dst host {})", self.source_port, destination),
+            None => format!("src port {}", self.source_port)
+        }
+    }
+
+    /// Moving average of the size of the stream's packets, in bytes, leaving out keepalives
+    pub fn average_packet_size(&self) -> u16 {
+        self.average_packet_size
+    }
+
+    /// When the last packet (that wasn't a keepalive) was seen
+    pub fn last_seen(&self) -> PacketTime {
+        self.last_packet_seen
+    }
+
+    /// When the first packet from this port was seen
+    pub fn first_seen(&self) -> PacketTime {
+        self.first_seen
+    }
+
+    /// How many packets of the averaging window have been filled, up to `BITRATE_WINDOW_SIZE`
+    pub fn window_fill(&self) -> u16 {
+        self.window_size
+    }
+
+    /// True once enough packets have come in to fill the averaging window, so the average is meaningful
+    pub fn is_established(&self) -> bool {
+        self.window_size >= BITRATE_WINDOW_SIZE
+    }
+
+    /// Estimated bitrate of the stream in kilobits per second, once enough packets have come in to tell
+    pub fn bitrate_kbps(&self) -> Option<u32> {
+        if !self.is_established() || self.average_interval_us == 0 {
+            return None;
+        }
+
+        // Bytes per microsecond * 8000 gives kilobits per second
+        Some((u64::from(self.average_packet_size) * 8000 / u64::from(self.average_interval_us)) as u32)
+    }
+
+    /// Add a single packet to the stream, causing the average size and timestamp to update
+    ///
+    /// Note that with `KeepalivePolicy::Drop`, packets smaller than `average_packet_size / DROP_FACTOR` will be ignored
+    /// (and won't update the last seen timestamp)
+    ///
+    /// # Arguments
+    /// * `packet_length` - Length of the packet
+    /// * `time` - When the packet arrived. pcap's capture time may be a little before it's processed (or long before,
+    ///   when reading a file)
+    /// * `keepalive_policy` - Whether to ignore keepalive packets
+    /// * `alpha` - Weight the packet gets in the moving averages, between 0 and 1
+    pub fn add_packet(&mut self, packet_length: u16, time: PacketTime, keepalive_policy: KeepalivePolicy, alpha: f64) {
+        // If the packet is less than 1/DROP_FACTOR the size of the average, ignore it, it's a keepalive. Widened first, as
+        // packets over 13KB (possible on loopback or with offloading) would overflow a u16
+        let is_keepalive = u32::from(packet_length) * u32::from(DROP_FACTOR) < self.average_packet_size.into();
+        if keepalive_policy == KeepalivePolicy::Include || !is_keepalive {
+            let smooth = |average: f64, sample: f64| average * (1.0 - alpha) + sample * alpha;
+
+            self.average_packet_size = smooth(self.average_packet_size.into(), packet_length.into()).round() as u16;
+
+            // pcap's timestamps are much more precise than when we got round to reading the packets
+            let interval_us = (time.captured_at - self.last_packet_seen.captured_at).num_microseconds()
+                .map_or(u32::MAX, |interval| interval.clamp(0, u32::MAX.into()) as u32);
+            self.average_interval_us = smooth(self.average_interval_us.into(), interval_us.into()).round() as u32;
+
+            self.last_packet_seen = time;
+
+            if self.window_size < BITRATE_WINDOW_SIZE {
+                self.window_size += 1;
+            }
+        }
+    }
+}
+
+/// Longest IP header we need to keep: IPv4 with the maximum 40 bytes of options (IPv6's fixed header is only 40)
+const MAX_IP_HEADER_LEN: i32 = 60;
+
+/// Length of a UDP header
+const UDP_HEADER_LEN: i32 = 8;
+
+/// Bytes of UDP payload needed to recognise a STUN message: its type, length and magic cookie
+const STUN_PREFIX_LEN: i32 = 8;
+
+/// Fixed value in bytes 4-7 of every STUN message (RFC 5389)
+const STUN_MAGIC_COOKIE: [u8; 4] = [0x21, 0x12, 0xA4, 0x42];
+
+/// Length of the link layer header for a datalink type, allowing for optional extras like VLAN tags
+///
+/// Unrecognised link types get a generous allowance rather than risking truncation.
+fn link_header_len(linktype: Linktype) -> i32 {
+    match linktype.0 {
+        // Ethernet, with room for up to two 802.1Q VLAN tags
+        1 => 14 + 2 * 4,
+        // Linux "cooked" captures, v1 and v2
+        113 => 16,
+        276 => 20,
+        // BSD loopback and OpenBSD loopback
+        0 | 108 => 4,
+        // Raw IP
+        12 | 14 | 101 => 0,
+        _ => 64
+    }
+}
+
+/// Smallest snaplen that's guaranteed to capture the whole UDP header, and enough payload to spot STUN, on this type of link
+fn safe_snaplen(linktype: Linktype) -> i32 {
+    link_header_len(linktype) + MAX_IP_HEADER_LEN + UDP_HEADER_LEN + STUN_PREFIX_LEN
+}
+
+/// Construct and start a packet capture
+///
+/// # Arguments
+/// * `capture_device` - Device to capture from
+/// * `options` - Options controlling the capture
+/// * `filter` - BPF filter to apply to the capture
+fn get_capture(capture_device: CustomDevice, options: &CaptureOptions, filter: String) -> Result<Capture<Active>, ZoomTallyError> {
+    let snaplen = match options.snaplen {
+        Some(snaplen) => snaplen,
+        None => {
+            // The link type is only known once a capture is open, so briefly open one to find out how much to keep
+            let probe_cap = Capture::from_device(capture_device.to_pcap_device())?.open()?;
+            safe_snaplen(probe_cap.get_datalink())
+        }
+    };
+
+    let mut inactive_cap = Capture::from_device(capture_device.to_pcap_device())?
+        .promisc(options.promisc)
+        .snaplen(snaplen)
+        .timeout(options.capture_timeout_ms);
+
+    if let Some(buffer_size) = options.buffer_size {
+        inactive_cap = inactive_cap.buffer_size(buffer_size);
+    }
+
+    let own_addresses = options.own_addresses(&capture_device);
+
+    let mut cap = inactive_cap.open()?;
+    cap.filter(&build_filter(filter, options, own_addresses))?;
+
+    Ok(cap)
+}
+
+/// Add the IP version and source address restrictions from the options to a BPF filter
+///
+/// # Arguments
+/// * `filter` - Filter to restrict
+/// * `options` - Options controlling the capture
+/// * `own_addresses` - Only match packets sent from these addresses, or `None` to match any sender
+fn build_filter(filter: String, options: &CaptureOptions, own_addresses: Option<&[IpAddr]>) -> String {
+    let filter = match options.ip_family {
+        Some(family) => format!("{} && ({})", family.filter(), filter),
+        None => filter
+    };
+
+    match own_addresses {
+        Some(addresses) if !addresses.is_empty() => {
+            let own_hosts: Vec<String> = addresses.iter().map(|address| format!("src host {}", address)).collect();
+            format!("({}) && ({})", filter, own_hosts.join(" || "))
+        },
+        _ => filter
+    }
+}
+
+/// BPF filter matching the traffic discovery mode classifies: UDP to the Zoom ports
+fn discovery_filter(options: &CaptureOptions) -> String {
+    format!("udp && {}", destination_filter(&options.zoom_ports))
+}
+
+/// What the capture gave back when asked for the next packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NextPacket {
+    /// A UDP packet, with its source port, length, destination address and when it arrived
+    Udp(u16, u16, IpAddr, PacketTime),
+    /// A STUN/ICE negotiation packet, which isn't part of any media or control stream
+    Stun,
+    /// A packet we couldn't find a UDP header in, e.g. because of unusual IP headers
+    Undecodable,
+    /// No packets arrived before the capture timed out
+    Timeout
+}
+
+/// True if a UDP payload looks like a STUN message: top two bits clear, then the magic cookie after the length
+fn is_stun(payload: &[u8]) -> bool {
+    payload.len() >= STUN_PREFIX_LEN as usize && payload[0] & 0xC0 == 0 && payload[4..8] == STUN_MAGIC_COOKIE
+}
+
+/// When pcap captured a packet, from its header
+///
+/// Falls back to now if the timestamp is out of range, which shouldn't happen for a real capture.
+// time_t and suseconds_t vary in width between platforms, so the casts aren't always no-ops
+#[allow(clippy::unnecessary_cast)]
+fn captured_at(packet: &Packet) -> DateTime<Utc> {
+    let timestamp = packet.header.ts;
+    Utc.timestamp_opt(timestamp.tv_sec as i64, timestamp.tv_usec as u32 * 1000).single().unwrap_or_else(Utc::now)
+}
+
+/// Slice a captured frame down to its IP packet and above, skipping whatever link layer header the capture has
+fn slice_frame(data: &[u8], linktype: Linktype) -> Option<SlicedPacket<'_>> {
+    let ip_after = match linktype.0 {
+        // Linux "cooked" captures, v1 and v2, and BSD and OpenBSD loopback
+        113 => 16,
+        276 => 20,
+        0 | 108 => 4,
+        // Raw IP
+        12 | 14 | 101 => 0,
+        // Ethernet, and anything else we don't know about, which is most likely Ethernet too
+        _ => return SlicedPacket::from_ethernet(data).ok()
+    };
+
+    data.get(ip_after..).and_then(|ip| SlicedPacket::from_ip(ip).ok())
+}
+
+/// Given a captured frame, extract the UDP source port, packet length, destination address and capture time
+///
+/// Returns `NextPacket::Undecodable` if the frame can't be sliced down to a UDP header, despite the UDP filter.
+///
+/// # Arguments
+/// * `data` - The frame, starting with the capture's link layer header
+/// * `linktype` - The capture's link type, to know which link layer header that is
+/// * `captured_at` - When pcap captured the frame
+fn unpack_packet(data: &[u8], linktype: Linktype, captured_at: DateTime<Utc>) -> NextPacket {
+    let parsed_packet = match slice_frame(data, linktype) {
+        Some(parsed_packet) => parsed_packet,
+        None => return NextPacket::Undecodable
+    };
+
+    let destination = match parsed_packet.ip {
+        Some(InternetSlice::Ipv4(ip_header)) => IpAddr::V4(ip_header.destination_addr()),
+        Some(InternetSlice::Ipv6(ip_header, _)) => IpAddr::V6(ip_header.destination_addr()),
+        None => return NextPacket::Undecodable
+    };
+
+    match parsed_packet.transport {
+        Some(TransportSlice::Udp(_)) if is_stun(parsed_packet.payload) => NextPacket::Stun,
+        Some(TransportSlice::Udp(udp_header)) => NextPacket::Udp(udp_header.source_port(), udp_header.length(), destination,
+            PacketTime { captured_at, seen_at: Instant::now() }),
+        _ => NextPacket::Undecodable
+    }
+}
+
+/// Somewhere the capture loops can read packets from, so they don't depend on a live pcap capture
+pub trait PacketSource {
+    /// Wait for the next packet and extract its source port and length
+    ///
+    /// Returns an error when the source has failed or run out of packets, which ends the capture loop.
+    fn next_packet(&mut self) -> Result<NextPacket, ZoomTallyError>;
+
+    /// Received and dropped packet counts, if the source keeps them
+    fn stats(&mut self) -> Result<pcap::Stat, ZoomTallyError>;
+}
+
+/// Live and offline (file) pcap captures
+impl<T: Activated + ?Sized> PacketSource for Capture<T> {
+    fn next_packet(&mut self) -> Result<NextPacket, ZoomTallyError> {
+        let linktype = self.get_datalink();
+        match self.next() {
+            Ok(packet) => Ok(unpack_packet(&packet, linktype, captured_at(&packet))),
+            Err(pcap::Error::TimeoutExpired) => Ok(NextPacket::Timeout),
+            Err(e) => Err(e.into())
+        }
+    }
+
+    fn stats(&mut self) -> Result<pcap::Stat, ZoomTallyError> {
+        Ok(Capture::stats(self)?)
+    }
+}
+
+/// A saved capture file, read with the packets' monotonic times spaced out as they were captured rather than as
+/// fast as they're read
+struct ReplayCapture {
+    cap: Capture<Offline>,
+    /// When reading started, which the first packet's monotonic time is set to
+    started_at: Instant,
+    /// When the first packet in the file was captured
+    first_captured_at: Option<DateTime<Utc>>
+}
+
+impl PacketSource for ReplayCapture {
+    fn next_packet(&mut self) -> Result<NextPacket, ZoomTallyError> {
+        Ok(match self.cap.next_packet()? {
+            NextPacket::Udp(port, length, destination, time) => {
+                let first_captured_at = *self.first_captured_at.get_or_insert(time.captured_at);
+                // Clock steps recorded in the file move packets back to the start rather than before it
+                let offset = (time.captured_at - first_captured_at).to_std().unwrap_or_default();
+                NextPacket::Udp(port, length, destination, PacketTime { seen_at: self.started_at + offset, ..time })
+            },
+            next => next
+        })
+    }
+
+    fn stats(&mut self) -> Result<pcap::Stat, ZoomTallyError> {
+        Ok(self.cap.stats()?)
+    }
+}
+
+/// Packets held in memory, e.g. to drive the capture loops in tests and benchmarks without a device or file
+///
+/// Yields each packet in turn, then reports the end of the packets the same way pcap does at the end of a file.
+pub struct VecPacketSource {
+    packets: std::vec::IntoIter<NextPacket>
+}
+
+impl VecPacketSource {
+    pub fn new(packets: Vec<NextPacket>) -> VecPacketSource {
+        VecPacketSource { packets: packets.into_iter() }
+    }
+}
+
+impl PacketSource for VecPacketSource {
+    fn next_packet(&mut self) -> Result<NextPacket, ZoomTallyError> {
+        self.packets.next().ok_or(ZoomTallyError::Capture(pcap::Error::NoMorePackets))
+    }
+
+    fn stats(&mut self) -> Result<pcap::Stat, ZoomTallyError> {
+        Err(ZoomTallyError::Capture(pcap::Error::PcapError("packets in memory don't keep capture stats".to_string())))
+    }
+}
+
+/// Wait for the next packet from a source, or `None` once it's run out of packets, e.g. at the end of a file
+///
+/// Any other error means the capture has failed, e.g. because the device has gone away, and is passed on so it can be
+/// reported rather than leaving the statuses to go stale.
+fn next_or_end(source: &mut dyn PacketSource) -> Result<Option<NextPacket>, ZoomTallyError> {
+    match source.next_packet() {
+        Ok(next) => Ok(Some(next)),
+        Err(ZoomTallyError::Capture(pcap::Error::NoMorePackets)) => Ok(None),
+        Err(e) => Err(e)
+    }
+}
+
+/// Let the main thread know the capture loop is still alive, and count the packet it just handled (if any)
+///
+/// Returns false if the channel map has been poisoned by the main thread panicking, so there's nobody left to report
+/// to and the capture loop should stop.
+fn record_capture_cycle(channel_map: &RwLock<zoom_channels::ZoomChannels>, next: NextPacket) -> bool {
+    let mut write_map = match channel_map.write() {
+        Ok(write_map) => write_map,
+        Err(_) => return false
+    };
+    write_map.last_capture_cycle = Instant::now();
+    match next {
+        NextPacket::Udp(..) => write_map.packets_processed += 1,
+        NextPacket::Stun => write_map.stun_packets += 1,
+        NextPacket::Undecodable => write_map.undecodable_packets += 1,
+        NextPacket::Timeout => ()
+    }
+    true
+}
+
+/// Periodically logs the capture's received and dropped packet counts to stderr
+struct StatsLogger {
+    interval: Option<Duration>,
+    last_logged: Instant
+}
+
+impl StatsLogger {
+    fn new(interval_secs: Option<u32>) -> StatsLogger {
+        StatsLogger {
+            interval: interval_secs.map(|secs| Duration::seconds(secs.into())),
+            last_logged: Instant::now()
+        }
+    }
+
+    /// Log the capture statistics if it's been at least an interval since they were last logged
+    fn maybe_log(&mut self, source: &mut dyn PacketSource) {
+        if let Some(interval) = self.interval {
+            let now = Instant::now();
+            if elapsed_since(now, self.last_logged) >= interval {
+                match source.stats() {
+                    Ok(stats) => eprintln!("Capture stats: received={} dropped={} if_dropped={}",
+                        stats.received, stats.dropped, stats.if_dropped),
+                    Err(e) => eprintln!("Couldn't get capture stats: {}", e)
+                }
+                self.last_logged = now;
+            }
+        }
+    }
+}
+
+/// A live capture that switches to a long timeout when no packets have arrived for a while, and back to the normal
+/// one as soon as a packet turns up
+///
+/// pcap can't change the timeout of an open capture, so each switch reopens it.
+struct PowerSavingCapture {
+    capture_device: CustomDevice,
+    options: CaptureOptions,
+    filter: String,
+    cap: Capture<Active>,
+    last_packet_seen: Instant,
+    idle: bool
+}
+
+impl PowerSavingCapture {
+    fn new(capture_device: CustomDevice, options: &CaptureOptions, filter: String) -> Result<PowerSavingCapture, ZoomTallyError> {
+        Ok(PowerSavingCapture {
+            cap: get_capture(capture_device.clone(), options, filter.clone())?,
+            capture_device,
+            options: options.clone(),
+            filter,
+            last_packet_seen: Instant::now(),
+            idle: false
+        })
+    }
+
+    /// Reopen the capture with the long idle timeout, or the normal one
+    fn set_idle(&mut self, idle: bool) -> Result<(), ZoomTallyError> {
+        let capture_timeout_ms = if idle { POWER_SAVE_TIMEOUT_MSEC } else { self.options.capture_timeout_ms };
+        let options = CaptureOptions { capture_timeout_ms, ..self.options.clone() };
+
+        self.cap = get_capture(self.capture_device.clone(), &options, self.filter.clone())?;
+        self.idle = idle;
+        Ok(())
+    }
+}
+
+impl PacketSource for PowerSavingCapture {
+    fn next_packet(&mut self) -> Result<NextPacket, ZoomTallyError> {
+        let next = self.cap.next_packet()?;
+        let now = Instant::now();
+
+        match next {
+            NextPacket::Timeout => {
+                if !self.idle && elapsed_since(now, self.last_packet_seen) >= Duration::seconds(POWER_SAVE_IDLE_AFTER_SECS) {
+                    self.set_idle(true)?;
+                }
+            },
+            _ => {
+                self.last_packet_seen = now;
+                if self.idle {
+                    self.set_idle(false)?;
+                }
+            }
+        }
+
+        Ok(next)
+    }
+
+    fn stats(&mut self) -> Result<pcap::Stat, ZoomTallyError> {
+        Ok(self.cap.stats()?)
+    }
+}
+
+/// Open a live capture with the given filter, saving power while idle if the options ask for it
+fn open_source(capture_device: CustomDevice, options: &CaptureOptions, filter: String) -> Result<Box<dyn PacketSource>, ZoomTallyError> {
+    Ok(if options.power_save {
+        Box::new(PowerSavingCapture::new(capture_device, options, filter)?)
+    } else {
+        Box::new(get_capture(capture_device, options, filter)?)
+    })
+}
+
+/// Keep trying something that needs the capture device until it works, waiting longer after each failure
+///
+/// Used for `wait_for_device`, so we can start before the device exists or is up. Returns `None` if `stopped` is set
+/// while waiting.
+///
+/// # Arguments
+/// * `stopped` - Set to true to give up waiting
+/// * `attempt` - What to try
+pub fn retry_until_ready<T>(stopped: &SimpleAtomicBool, mut attempt: impl FnMut() -> Result<T, ZoomTallyError>) -> Option<T> {
+    let mut retry_after = Duration::milliseconds(WAIT_FOR_DEVICE_FIRST_RETRY_MSEC);
+    let mut reported = false;
+
+    loop {
+        match attempt() {
+            Ok(result) => return Some(result),
+            Err(e) => {
+                // Only say why once, rather than every retry
+                if !reported {
+                    eprintln!("Waiting for the capture device: {}", e);
+                    reported = true;
+                }
+            }
+        }
+
+        let waiting_since = Instant::now();
+        while elapsed_since(Instant::now(), waiting_since) < retry_after {
+            if stopped.get() {
+                return None;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        retry_after = std::cmp::min(retry_after * 2, Duration::milliseconds(WAIT_FOR_DEVICE_MAX_RETRY_MSEC));
+    }
+}
+
+/// Open a live capture, waiting for the device to be ready if the options ask for it
+///
+/// Returns `None` if `stopped` is set while waiting.
+fn open_source_when_ready(capture_device: CustomDevice, options: &CaptureOptions, filter: String, stopped: &SimpleAtomicBool)
+                          -> Result<Option<Box<dyn PacketSource>>, ZoomTallyError> {
+    if !options.wait_for_device {
+        return open_source(capture_device, options, filter).map(Some);
+    }
+
+    Ok(retry_until_ready(stopped, || open_source(capture_device.clone(), options, filter.clone())))
+}
+
+/// Decide which kind of stream a port is carrying from its average packet size, and record it in the channel map
+///
+/// Walks the size buckets in order and picks the first one the stream is above. Returns the name of the class chosen,
+/// or `"unknown"` if none fit (or the control slot is already taken).
+///
+/// # Arguments
+/// * `stream` - Stream to classify, which should have a full averaging window
+/// * `seen_at` - When the packet that triggered classification arrived
+/// * `options` - Options holding the size buckets to classify against
+/// * `channel_map` - Map of channels to update
+fn guess_stream_for_packet(stream: &mut PacketStream, seen_at: Instant, options: &CaptureOptions,
+                           channel_map: &mut zoom_channels::ZoomChannels) -> &'static str {
+    let kind = size_tier(stream.average_packet_size, options);
+
+    let previous_kind = channel_map.channel_for_stream(stream);
+
+    // A port that's only just been classified keeps its kind through brief bursts, unless it's clearly changed
+    let cooling_down = stream.last_classified_at.is_some_and(|classified_at| {
+        elapsed_since(seen_at, classified_at) < Duration::milliseconds(options.reclassify_cooldown_ms.into())
+    });
+    let kind = if previous_kind.is_some() && kind != previous_kind && cooling_down
+        && !crosses_by_margin(stream.average_packet_size, previous_kind, kind, &options.size_buckets) {
+        previous_kind
+    } else {
+        kind
+    };
+
+    if kind.is_some() && kind != previous_kind {
+        stream.last_classified_at = Some(seen_at);
+    }
+
+    // A port that's left a channel's size range can't be that channel any more, so it isn't left behind in its old
+    // slot looking like it's been reassigned all over again with every packet
+    let slots = [(&mut channel_map.video, ChannelKind::Video), (&mut channel_map.audio, ChannelKind::Audio),
+        (&mut channel_map.control, ChannelKind::Control), (&mut channel_map.screenshare, ChannelKind::Video)];
+    for (slot, slot_kind) in slots {
+        if kind != Some(slot_kind) && slot.is_some_and(|assigned| assigned.is_same_stream(stream)) {
+            *slot = None;
+        }
+    }
+
+    match kind {
+        Some(ChannelKind::Video) => assign_video_stream(stream, seen_at, channel_map),
+        Some(ChannelKind::Audio) => channel_map.audio = Some(*stream),
+        Some(ChannelKind::Control) => {
+            // Don't let another small stream steal the control slot while the real control port is still active
+            let control_active = channel_map.control.is_some_and(|control| {
+                !control.is_same_stream(stream)
+                    && elapsed_since(seen_at, control.last_packet_seen.seen_at) < Duration::milliseconds(CONTROL_STICKY_MSEC)
+            });
+
+            if control_active {
+                return "unknown";
+            }
+            channel_map.control = Some(*stream);
+        },
+        None => ()
+    }
 
-/// A stream of packets larger than this many bytes is probably video
-const VIDEO_ABOVE: u16 = 500;
+    if previous_kind.is_some() && previous_kind != kind {
+        channel_map.reassignments += 1;
+    }
 
-/// A single port sending a stream of packets to a remote server
-#[derive(Hash, Eq, PartialEq, Debug, Clone, Copy)]
-pub struct PacketStream {
-    pub source_port: u16,
-    pub average_packet_size: u16,
-    pub last_packet_seen: DateTime<Utc>,
-    window_size: u16
+    if options.log_reassignments && previous_kind.is_some() && previous_kind != kind {
+        eprintln!("Reassigned: port={} from_channel={} to_channel={} avg_size={}", stream.source_port,
+            previous_kind.map_or("none", |kind| kind.as_str()), kind.map_or("none", |kind| kind.as_str()),
+            stream.average_packet_size);
+    }
+
+    kind.map_or("unknown", |kind| kind.as_str())
 }
 
-impl PacketStream {
-    fn new(source_port: u16) -> PacketStream {
-        PacketStream {
-            source_port: source_port,
-            average_packet_size: 0,
-            last_packet_seen: Utc::now(),
-            window_size: 0
+/// Put a video sized stream in the video or screen share slot
+///
+/// Two video sized streams sending at once means one of them is a screen share, taken to be the one with the bigger
+/// packets as shared screens are sent at a higher resolution than webcams. A screen share on its own, with the camera
+/// off, can't be told apart from video, so is reported as video.
+fn assign_video_stream(stream: &PacketStream, seen_at: Instant, channel_map: &mut zoom_channels::ZoomChannels) {
+    if channel_map.screenshare.is_some_and(|screenshare| screenshare.is_same_stream(stream)) {
+        channel_map.screenshare = Some(*stream);
+        return;
+    }
+
+    let other_video = channel_map.video.filter(|video| {
+        !video.is_same_stream(stream)
+            && elapsed_since(seen_at, video.last_packet_seen.seen_at) < Duration::milliseconds(SCREENSHARE_OVERLAP_MSEC)
+    });
+
+    match other_video {
+        Some(video) if stream.average_packet_size > video.average_packet_size => channel_map.screenshare = Some(*stream),
+        Some(video) => {
+            channel_map.screenshare = Some(video);
+            channel_map.video = Some(*stream);
+        },
+        None => channel_map.video = Some(*stream)
+    }
+}
+
+/// Replace the copy of a stream in whichever channel it's assigned to, returning the channel's name if it is assigned
+fn update_assigned_stream(stream: &PacketStream, channel_map: &mut zoom_channels::ZoomChannels) -> Option<&'static str> {
+    let channels = [(&mut channel_map.video, "video"), (&mut channel_map.audio, "audio"), (&mut channel_map.control, "control"),
+        (&mut channel_map.screenshare, "screenshare")];
+
+    for (channel, name) in channels {
+        if channel.is_some_and(|channel| channel.is_same_stream(stream)) {
+            *channel = Some(*stream);
+            return Some(name);
         }
     }
+    None
+}
 
-    /// Add a single packet to the stream, causing the average size and timestamp to update
-    ///
-    /// Note that packets smaller than `average_packet_size / DROP_FACTOR` will be ignored (and won't update the last seen timestamp)
-    ///
-    pub fn add_packet(&mut self, packet_length: u16) {
-        // If the packet is less than 1/DROP_FACTOR the size of the average, ignore it, it's a keepalive
-        if packet_length * DROP_FACTOR >= self.average_packet_size {
-            self.average_packet_size -= self.average_packet_size / BITRATE_WINDOW_SIZE;
-            self.average_packet_size += packet_length / BITRATE_WINDOW_SIZE;
+/// Streams worth following once discovery's finished: every one that's sent enough packets to be classified
+pub fn monitored_streams(channel_map: &zoom_channels::ZoomChannels) -> Vec<PacketStream> {
+    channel_map.streams.values().filter(|stream| stream.is_established()).copied().collect()
+}
 
-            self.last_packet_seen = Utc::now();
+/// Which size bucket an average packet size falls into, if any
+fn size_tier(average_packet_size: u16, options: &CaptureOptions) -> Option<ChannelKind> {
+    options.size_buckets.iter()
+        .find(|bucket| average_packet_size > bucket.above)
+        .map(|bucket| bucket.kind)
+        .filter(|kind| options.control_channel || *kind != ChannelKind::Control)
+}
 
-            if self.window_size < BITRATE_WINDOW_SIZE {
-                self.window_size += 1;
+/// Count the streams seen so far into the channel map, and which size tier each falls into
+fn record_ports_seen(stream_map: &HashMap<StreamKey, PacketStream>, options: &CaptureOptions, channel_map: &mut zoom_channels::ZoomChannels) {
+    let mut ports_by_tier = zoom_channels::PortTiers::default();
+    for stream in stream_map.values() {
+        ports_by_tier.add(size_tier(stream.average_packet_size, options));
+    }
+
+    channel_map.ports_seen = stream_map.len();
+    channel_map.ports_by_tier = ports_by_tier;
+}
+
+/// True if an average packet size is well past the threshold between two kinds of stream, rather than just over it
+fn crosses_by_margin(average_packet_size: u16, from: Option<ChannelKind>, to: Option<ChannelKind>, size_buckets: &[SizeBucket]) -> bool {
+    let threshold_for = |kind: Option<ChannelKind>| size_buckets.iter().find(|bucket| Some(bucket.kind) == kind).map(|bucket| u32::from(bucket.above));
+
+    let average = u32::from(average_packet_size);
+    match (threshold_for(from), threshold_for(to)) {
+        // Moving up a tier means going over the new tier's threshold, moving down means dropping under the old one's
+        (Some(from_above), Some(to_above)) if to_above > from_above => average * 100 > to_above * (100 + RECLASSIFY_MARGIN_PERCENT),
+        (Some(from_above), Some(_)) => average * 100 < from_above * (100 - RECLASSIFY_MARGIN_PERCENT),
+        // Without thresholds to compare against, don't hold the stream back
+        _ => true
+    }
+}
+
+/// Print a line of CSV describing a packet and the state of the stream it was added to
+///
+/// Columns are `timestamp,port,length,avg_size,class`
+fn trace_packet(stream: &PacketStream, packet_length: u16, captured_at: DateTime<Utc>, class: &str) {
+    println!("{},{},{},{},{}", captured_at.to_rfc3339(), stream.source_port, packet_length, stream.average_packet_size, class);
+}
+
+/// Add a packet to its port's stream, and classify the stream once enough packets have come in
+///
+/// # Arguments
+/// * `stream_map` - Streams seen so far, by source port (and destination, if streams are told apart by it)
+/// * `port` - Source port of the packet
+/// * `destination` - Address the packet was sent to
+/// * `length` - Length of the packet
+/// * `time` - When the packet arrived
+/// * `options` - Options controlling classification and tracing
+/// * `channel_map` - Map of channels to update when a stream is classified
+fn discover_packet(stream_map: &mut HashMap<StreamKey, PacketStream>, port: u16, destination: IpAddr, length: u16, time: PacketTime,
+                   options: &CaptureOptions, channel_map: &RwLock<zoom_channels::ZoomChannels>) {
+    let destination = if options.key_by_destination { Some(destination) } else { None };
+    let key = (port, destination);
+    let new_port = !stream_map.contains_key(&key);
+    let matched_stream = stream_map.entry(key).or_insert(PacketStream::new(port, destination, time));
+    matched_stream.add_packet(length, time, options.keepalive_policy, options.ema_alpha);
+    let established = matched_stream.is_established();
+
+    // The main thread's gone if the lock's poisoned, and record_capture_cycle will stop the loop
+    let class = match channel_map.write() {
+        Ok(mut write_map) => {
+            let class = if established {
+                // Enough packets have come in to decide which type of stream this is
+                guess_stream_for_packet(matched_stream, time.seen_at, options, &mut write_map)
+            } else {
+                "unknown"
+            };
+            write_map.streams.insert(key, *matched_stream);
+            if established || new_port {
+                record_ports_seen(stream_map, options, &mut write_map);
             }
+            class
+        },
+        Err(_) => "unknown"
+    };
+
+    if options.trace_csv {
+        trace_packet(&stream_map[&key], length, time.captured_at, class);
+    }
+}
+
+/// Works out size thresholds from the streams of the first call seen
+struct Calibration {
+    /// When the first stream became established, if one has
+    started_at: Option<Instant>
+}
+
+/// How calibration finished
+enum CalibrationOutcome {
+    /// Thresholds were worked out from the streams
+    Calibrated(Vec<SizeBucket>),
+    /// There weren't enough different streams to tell apart in time, so the size buckets stay as they were
+    GaveUp
+}
+
+impl Calibration {
+    fn new() -> Calibration {
+        Calibration { started_at: None }
+    }
+
+    /// Try to calibrate from the streams seen so far, returning how it finished once it has
+    ///
+    /// Calibration waits for a while after the first stream is established, so the rest of the call's streams can
+    /// turn up and settle, then keeps trying until there are enough different streams to tell apart, or it's been
+    /// trying too long.
+    fn update(&mut self, stream_map: &HashMap<StreamKey, PacketStream>, seen_at: Instant) -> Option<CalibrationOutcome> {
+        let average_sizes: Vec<u16> = stream_map.values()
+            .filter(|stream| stream.is_established())
+            .map(|stream| stream.average_packet_size)
+            .collect();
+        if average_sizes.is_empty() {
+            return None;
+        }
+
+        let started_at = *self.started_at.get_or_insert(seen_at);
+        let elapsed = elapsed_since(seen_at, started_at);
+        if elapsed < Duration::seconds(CALIBRATION_SECS) {
+            return None;
+        }
+
+        match SizeBucket::calibrate(&average_sizes) {
+            Some(buckets) => Some(CalibrationOutcome::Calibrated(buckets)),
+            None if elapsed >= Duration::seconds(CALIBRATION_GIVE_UP_SECS) => Some(CalibrationOutcome::GaveUp),
+            None => None
         }
     }
 }
 
-/// Construct and start a packet capture
+/// Open a capture on the device and compile a BPF filter for it, without capturing anything
+///
+/// Compiling can depend on the device's link type, so this is the only reliable way to tell whether a filter is valid.
+/// Returns the filter as it was compiled, with the IP version and source address restrictions from the options added.
 ///
 /// # Arguments
-/// * `capture_device` - Device to capture from
-/// * `filter` - BPF filter to apply to the capture
-fn get_capture(capture_device: CustomDevice, filter: String) -> Capture<Active> {
-    let mut cap = Capture::from_device(capture_device.to_pcap_device()).unwrap()
-        .promisc(false)
-        .snaplen(50)
-        .timeout(100)
-        .open().unwrap();
-    cap.filter(&filter).unwrap();
+/// * `capture_device` - Device to compile the filter for
+/// * `options` - Options controlling the capture
+/// * `filter` - BPF filter to compile
+pub fn compile_filter(capture_device: CustomDevice, options: &CaptureOptions, filter: &str) -> Result<String, ZoomTallyError> {
+    let own_addresses = options.own_addresses(&capture_device);
+    let filter = build_filter(filter.to_string(), options, own_addresses);
 
-    return cap;
+    let mut cap = Capture::from_device(capture_device.to_pcap_device())?
+        .promisc(options.promisc)
+        .timeout(options.capture_timeout_ms)
+        .open()?;
+    cap.filter(&filter)?;
+
+    Ok(filter)
 }
 
-/// Given a packet, extract the UDP source port and packet length and return a tuple
-fn unpack_packet(packet: Packet) -> (u16, u16) {
-    let parsed_packet = SlicedPacket::from_ethernet(&packet).unwrap();
+/// Result of checking whether the discovery filter matches anything
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FilterCheck {
+    /// Packets the discovery filter matched
+    pub matched: u64,
+    /// Packets seen on the device at all
+    pub received: u32
+}
 
-    return match parsed_packet.transport {
-        Some(TransportSlice::Udp(udp_header)) => {
-            (udp_header.source_port(), udp_header.length())
-        },
-        _ => panic!("Unexpectedly got a non-UDP packet, despite applying a UDP filter")
+/// Capture for a short while with and without the discovery filter, to tell whether it matches any of the traffic
+///
+/// # Arguments
+/// * `capture_device` - Device to capture from
+/// * `options` - Options controlling the capture
+/// * `duration` - How long to watch for
+pub fn check_filter(capture_device: CustomDevice, options: &CaptureOptions, duration: Duration) -> Result<FilterCheck, ZoomTallyError> {
+    // pcap counts every packet the unfiltered capture receives, whether or not we read them
+    let mut unfiltered_cap = Capture::from_device(capture_device.to_pcap_device())?
+        .promisc(options.promisc)
+        .timeout(options.capture_timeout_ms)
+        .open()?;
+    let mut filtered_cap = get_capture(capture_device, options, discovery_filter(options))?;
 
+    let mut matched = 0;
+    let started_at = Instant::now();
+    while elapsed_since(Instant::now(), started_at) < duration {
+        match filtered_cap.next_packet()? {
+            NextPacket::Timeout => (),
+            _ => matched += 1
+        }
     }
+
+    Ok(FilterCheck { matched, received: unfiltered_cap.stats()?.received })
 }
 
 /// Implements a capture process that discovers which port is which (video, audio, control)
@@ -94,85 +1272,755 @@ pub struct PortDiscoveryCapture ();
 impl PortDiscoveryCapture {
     /// Start a capture to detect which port is which
     ///
-    /// Watches for outgoing UDP packets to port 8801 and measures their size to guess which is audio, which is video and
+    /// Watches for outgoing UDP packets to the Zoom ports (normally 8801) and measures their size to guess which is audio, which is video and
     /// which is the control port. Expects to be run in a thread and report back to the main thread.
     ///
     /// # Arguments
     /// * `capture_device` - Device (as known to the system) to capture packets on
+    /// * `options` - Options controlling the capture
     /// * `channel_map` - This will be updated with each port as detections are made
     /// * `stopped` - Set to true to cause the thread to exit
-    pub fn run(capture_device: CustomDevice, channel_map: Arc<RwLock<zoom_channels::ZoomChannels>>, stopped: &SimpleAtomicBool) {
-        let mut cap = get_capture(capture_device, "udp && dst port 8801".to_string());
-        let mut stream_map = HashMap::new();
+    pub fn run(capture_device: CustomDevice, options: CaptureOptions, channel_map: Arc<RwLock<zoom_channels::ZoomChannels>>,
+               stopped: &SimpleAtomicBool) -> Result<(), ZoomTallyError> {
+        if let Some(mut source) = open_source_when_ready(capture_device, &options, discovery_filter(&options), stopped)? {
+            PortDiscoveryCapture::discover(source.as_mut(), &options, &channel_map, stopped)?;
+        }
+        Ok(())
+    }
+
+    /// Classify the packets from a source until it runs out or `stopped` is set
+    ///
+    /// Returns an error if the source fails, rather than runs out.
+    ///
+    /// # Arguments
+    /// * `source` - Where to read packets from, already filtered to the Zoom traffic
+    /// * `options` - Options controlling classification and tracing
+    /// * `channel_map` - This will be updated with each port as detections are made
+    /// * `stopped` - Set to true to stop reading
+    pub fn discover(source: &mut dyn PacketSource, options: &CaptureOptions, channel_map: &RwLock<zoom_channels::ZoomChannels>,
+                    stopped: &SimpleAtomicBool) -> Result<(), ZoomTallyError> {
+        // Carry on from any streams an earlier discovery found, so coming back from monitor mode doesn't start over
+        let mut stream_map: HashMap<StreamKey, PacketStream> = match channel_map.read() {
+            Ok(read_map) => read_map.streams.iter().map(|(key, stream)| (*key, *stream)).collect(),
+            Err(_) => return Ok(())
+        };
+        let mut options = options.clone();
+        // Calibration only happens once, rather than starting again each time discovery does
+        let calibration_done = channel_map.read().map_or(true, |read_map| read_map.calibration_done);
+        let mut calibration = if options.calibrate && !calibration_done { Some(Calibration::new()) } else { None };
 
-        while let Ok(packet) = cap.next() {
-            let (port, length) = unpack_packet(packet);
+        let mut stats_logger = StatsLogger::new(options.stats_interval_secs);
 
-            let matched_stream = stream_map.entry(port).or_insert(PacketStream::new(port));
-            matched_stream.add_packet(length);
+        while let Some(next) = next_or_end(source)? {
+            if let NextPacket::Udp(port, length, destination, time) = next {
+                discover_packet(&mut stream_map, port, destination, length, time, &options, channel_map);
 
-            if matched_stream.window_size >= BITRATE_WINDOW_SIZE {
-                // Enough packets have come in to decide which type of stream this is
-                {
-                    let mut write_map = channel_map.write().unwrap();
-                    if matched_stream.average_packet_size > VIDEO_ABOVE {
-                        write_map.video = Some(matched_stream.clone());
-                    } else if matched_stream.average_packet_size > AUDIO_ABOVE {
-                        write_map.audio = Some(matched_stream.clone());
-                    } else {
-                        write_map.control = Some(matched_stream.clone());
+                if let Some(outcome) = calibration.as_mut().and_then(|calibration| calibration.update(&stream_map, time.seen_at)) {
+                    calibration = None;
+                    if let CalibrationOutcome::Calibrated(buckets) = &outcome {
+                        options.size_buckets = buckets.clone();
+                    }
+                    if let Ok(mut write_map) = channel_map.write() {
+                        write_map.calibration_done = true;
+                        if let CalibrationOutcome::Calibrated(buckets) = outcome {
+                            write_map.calibrated_buckets = Some(buckets);
+                        }
                     }
                 }
             }
 
+            if !record_capture_cycle(channel_map, next) {
+                break;
+            }
+            stats_logger.maybe_log(source);
+
             if stopped.get() {
                 break;
             }
         }
+
+        Ok(())
+    }
+
+    /// Run discovery over every packet in a saved capture file, returning the channels it found
+    ///
+    /// Only the destination port and IP version restrictions in the options apply, since the machine that recorded
+    /// the file isn't known.
+    ///
+    /// # Arguments
+    /// * `path` - pcap file to read
+    /// * `options` - Options controlling classification
+    pub fn replay(path: &Path, options: &CaptureOptions) -> Result<zoom_channels::ZoomChannels, ZoomTallyError> {
+        let mut cap = Capture::from_file(path)?;
+        cap.filter(&build_filter(discovery_filter(options), options, None))?;
+        let mut source = ReplayCapture { cap, started_at: Instant::now(), first_captured_at: None };
+
+        let channel_map = RwLock::new(zoom_channels::ZoomChannels::new());
+
+        // Stats aren't meaningful for a file, and reading stops by itself at the end of it
+        let options = CaptureOptions { stats_interval_secs: None, ..options.clone() };
+        PortDiscoveryCapture::discover(&mut source, &options, &channel_map, &SimpleAtomicBool::new(false))?;
+
+        Ok(channel_map.into_inner().unwrap())
     }
 }
 
-/// Implements a capture process that watches the audio and video ports only, and updates their last packet times
+/// Implements a capture process that watches only the ports discovery found, and updates their last packet times
 pub struct PortMonitorCapture ();
 
 impl PortMonitorCapture {
-    /// Start a capture to detect when the audio and video ports were last used
+    /// Start a capture to detect when the ports discovery found were last used
     ///
-    /// Watches for outgoing UDP packets on the audio and video ports, and reports when they last had a packet seen.
-    /// Expects to be run in a thread and report back to the main thread.
+    /// Watches for outgoing UDP packets on every port discovery established, not just the ones assigned to a channel,
+    /// and reports when they last had a packet seen. Unassigned ports are still classified, so a screen share or a
+    /// port that's grown into audio or video is picked up. If there aren't any established streams to watch, it falls
+    /// back to discovery instead. Expects to be run in a thread and report back to the main thread.
     ///
     /// # Arguments
     /// * `capture_device` - Device (as known to the system) to capture packets on
-    /// * `channel_map` - Existing map of audio and video ports, to update as detections are made
+    /// * `options` - Options controlling the capture
+    /// * `channel_map` - Existing map of streams, to update as detections are made
     /// * `stopped` - Set to true to cause the thread to exit
-    pub fn run(capture_device: CustomDevice, channel_map: Arc<RwLock<zoom_channels::ZoomChannels>>, stopped: &SimpleAtomicBool) {
-        let mut video_stream;
-        let mut audio_stream;
-        {
-            let read_map = channel_map.read().unwrap();
-            video_stream = read_map.video.unwrap().clone();
-            audio_stream = read_map.audio.unwrap().clone();
-        }
-
-        let mut cap = get_capture(capture_device, format!("udp && (src port {} || src port {})", video_stream.source_port, audio_stream.source_port));
-
-        while let Ok(packet) = cap.next() {
-            let (port, length) = unpack_packet(packet);
-
-            {
-                let mut write_map = channel_map.write().unwrap();
-                if port == video_stream.source_port {
-                    video_stream.add_packet(length);
-                    write_map.video = Some(video_stream.clone());
-                } else if port == audio_stream.source_port {
-                    audio_stream.add_packet(length);
-                    write_map.audio = Some(audio_stream.clone());
+    pub fn run(capture_device: CustomDevice, options: CaptureOptions, channel_map: Arc<RwLock<zoom_channels::ZoomChannels>>,
+               stopped: &SimpleAtomicBool) -> Result<(), ZoomTallyError> {
+        let port_filters: Vec<String> = monitored_streams(&channel_map.read().unwrap()).iter()
+            .map(|stream| stream.filter())
+            .collect();
+        if port_filters.is_empty() {
+            // An empty list of ports isn't a valid filter, and there'd be nothing to see anyway
+            return PortDiscoveryCapture::run(capture_device, options, channel_map, stopped);
+        }
+
+        let filter = format!("udp && ({}) && {}", port_filters.join(" || "), destination_filter(&options.zoom_ports));
+        if let Some(mut source) = open_source_when_ready(capture_device, &options, filter, stopped)? {
+            PortMonitorCapture::monitor(source.as_mut(), &options, &channel_map, stopped)?;
+        }
+        Ok(())
+    }
+
+    /// Update the known streams from the packets from a source until it runs out or `stopped` is set
+    ///
+    /// Returns an error if the source fails, rather than runs out.
+    ///
+    /// Streams already assigned to a channel keep it, as in discovery a quiet camera could otherwise lose the video
+    /// slot. The rest are classified as they would be in discovery.
+    ///
+    /// # Arguments
+    /// * `source` - Where to read packets from, already filtered to the known ports
+    /// * `options` - Options controlling classification and tracing
+    /// * `channel_map` - Existing map of streams, to update as packets arrive
+    /// * `stopped` - Set to true to stop reading
+    pub fn monitor(source: &mut dyn PacketSource, options: &CaptureOptions, channel_map: &RwLock<zoom_channels::ZoomChannels>,
+                   stopped: &SimpleAtomicBool) -> Result<(), ZoomTallyError> {
+        let mut streams = monitored_streams(&channel_map.read().unwrap());
+
+        let mut stats_logger = StatsLogger::new(options.stats_interval_secs);
+
+        while let Some(next) = next_or_end(source)? {
+            if let NextPacket::Udp(port, length, destination, time) = next {
+                if let Some(stream) = streams.iter_mut().find(|stream| stream.matches(port, destination)) {
+                    stream.add_packet(length, time, options.keepalive_policy, options.ema_alpha);
+
+                    let mut write_map = match channel_map.write() {
+                        Ok(write_map) => write_map,
+                        Err(_) => break
+                    };
+                    let class = match update_assigned_stream(stream, &mut write_map) {
+                        Some(class) => class,
+                        None => guess_stream_for_packet(stream, time.seen_at, options, &mut write_map)
+                    };
+                    write_map.streams.insert(stream.key(), *stream);
+                    drop(write_map);
+
+                    if options.trace_csv {
+                        trace_packet(stream, length, time.captured_at, class);
+                    }
                 }
             }
 
+            if !record_capture_cycle(channel_map, next) {
+                break;
+            }
+            stats_logger.maybe_log(source);
+
             if stopped.get() {
                 break;
             }
         }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    const SERVER: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+    /// A UDP packet from a port to the server, `at_ms` after `start`
+    fn udp(start: Instant, port: u16, length: u16, at_ms: u64) -> NextPacket {
+        let offset = std::time::Duration::from_millis(at_ms);
+        let time = PacketTime {
+            captured_at: Utc.timestamp_opt(1_600_000_000, 0).unwrap() + Duration::from_std(offset).unwrap(),
+            seen_at: start + offset
+        };
+        NextPacket::Udp(port, length, SERVER, time)
+    }
+
+    /// Run discovery over some packets, starting from an empty channel map
+    fn discover(packets: Vec<NextPacket>, options: &CaptureOptions) -> zoom_channels::ZoomChannels {
+        let channel_map = RwLock::new(zoom_channels::ZoomChannels::new());
+        PortDiscoveryCapture::discover(&mut VecPacketSource::new(packets), options, &channel_map, &SimpleAtomicBool::new(false))
+            .unwrap();
+        channel_map.into_inner().unwrap()
+    }
+
+    /// Interleaved video, audio and control streams, 20ms apart
+    fn call_packets(start: Instant, count: u64) -> Vec<NextPacket> {
+        (0..count).flat_map(|i| vec![
+            udp(start, 50000, 1000, i * 20),
+            udp(start, 50001, 200, i * 20 + 1),
+            udp(start, 50002, 60, i * 20 + 2)
+        ]).collect()
+    }
+
+    /// A stream fed packets of the given lengths, 20ms apart
+    fn stream_of(lengths: impl IntoIterator<Item = u16>, keepalive_policy: KeepalivePolicy, alpha: f64) -> PacketStream {
+        let start = Instant::now();
+        let time_at = |i: u64| match udp(start, 50000, 0, i * 20) {
+            NextPacket::Udp(_, _, _, time) => time,
+            _ => unreachable!()
+        };
+
+        let mut stream = PacketStream::new(50000, None, time_at(0));
+        for (i, length) in lengths.into_iter().enumerate() {
+            stream.add_packet(length, time_at(i as u64), keepalive_policy, alpha);
+        }
+        stream
+    }
+
+    #[test]
+    fn add_packet_counts_keepalives_only_when_asked_to() {
+        let lengths = || std::iter::repeat_n(1000, 30).chain(std::iter::repeat_n(50, 10));
+
+        let dropped = stream_of(lengths(), KeepalivePolicy::Drop, DEFAULT_EMA_ALPHA);
+        assert!(dropped.average_packet_size() > 900);
+        assert_eq!(dropped.last_seen().seen_at - dropped.first_seen().seen_at, std::time::Duration::from_millis(29 * 20));
+
+        let included = stream_of(lengths(), KeepalivePolicy::Include, DEFAULT_EMA_ALPHA);
+        assert!(included.average_packet_size() < 500);
+        assert_eq!(included.last_seen().seen_at - included.first_seen().seen_at, std::time::Duration::from_millis(39 * 20));
+    }
+
+    #[test]
+    fn add_packet_converges_faster_with_a_larger_alpha() {
+        // Settle at 200 bytes, then jump to 1000 and see how far each average has moved after a few packets
+        let lengths = || std::iter::repeat_n(200, 100).chain(std::iter::repeat_n(1000, 5));
+
+        let smooth = stream_of(lengths(), KeepalivePolicy::Drop, 0.1).average_packet_size();
+        let responsive = stream_of(lengths(), KeepalivePolicy::Drop, 0.5).average_packet_size();
+        assert!(smooth < responsive, "{} should be behind {}", smooth, responsive);
+        assert!(responsive > 950);
+        assert!(smooth < 600);
+    }
+
+    #[test]
+    fn add_packet_converges_on_full_size_packets() {
+        // The average is rounded to whole bytes each time, so it stops short once a step would be under half a byte
+        let near = |average: u16, length: u16| f64::from(average.abs_diff(length)) <= 0.5 / DEFAULT_EMA_ALPHA;
+
+        let stream = stream_of(std::iter::repeat_n(1400, 200), KeepalivePolicy::Drop, DEFAULT_EMA_ALPHA);
+        assert!(near(stream.average_packet_size(), 1400), "{}", stream.average_packet_size());
+
+        // Jumbo and offloaded packets can be far bigger, and mustn't overflow either
+        let stream = stream_of(std::iter::repeat_n(u16::MAX, 200), KeepalivePolicy::Drop, DEFAULT_EMA_ALPHA);
+        assert!(near(stream.average_packet_size(), u16::MAX), "{}", stream.average_packet_size());
+    }
+
+    #[test]
+    fn discover_classifies_streams_by_size() {
+        let channels = discover(call_packets(Instant::now(), 20), &CaptureOptions::default());
+
+        assert_eq!(channels.video.map(|stream| stream.source_port()), Some(50000));
+        assert_eq!(channels.audio.map(|stream| stream.source_port()), Some(50001));
+        assert_eq!(channels.control.map(|stream| stream.source_port()), Some(50002));
+        assert_eq!(channels.packets_processed, 60);
+        assert_eq!(channels.ports_seen, 3);
+        assert_eq!(channels.reassignments, 0);
+    }
+
+    #[test]
+    fn discover_leaves_streams_unclassified_until_established() {
+        let channels = discover(call_packets(Instant::now(), u64::from(BITRATE_WINDOW_SIZE) - 1), &CaptureOptions::default());
+
+        assert_eq!(channels.video, None);
+        assert_eq!(channels.audio, None);
+        assert_eq!(channels.control, None);
+        assert_eq!(channels.ports_seen, 3);
+    }
+
+    #[test]
+    fn discover_reassigns_a_stream_that_changes_size() {
+        let start = Instant::now();
+        let packets = (0..40).map(|i| udp(start, 50000, if i < 15 { 200 } else { 1200 }, i * 20)).collect();
+        let channels = discover(packets, &CaptureOptions::default());
+
+        assert_eq!(channels.video.map(|stream| stream.source_port()), Some(50000));
+        assert_eq!(channels.reassignments, 1);
+    }
+
+    #[test]
+    fn discover_moves_a_stream_down_a_tier_only_once() {
+        let start = Instant::now();
+        let packets = (0..60).map(|i| udp(start, 50000, if i < 15 { 1200 } else { 200 }, i * 20)).collect();
+        let channels = discover(packets, &CaptureOptions::default());
+
+        assert_eq!(channels.video, None);
+        assert_eq!(channels.audio.map(|stream| stream.source_port()), Some(50000));
+        assert_eq!(channels.reassignments, 1);
+    }
+
+    #[test]
+    fn discover_splits_out_a_screen_share_alongside_the_video() {
+        let start = Instant::now();
+        let packets = (0..40).flat_map(|i| vec![udp(start, 50000, 1000, i * 20), udp(start, 50003, 1300, i * 20 + 1)])
+            .collect();
+        let channels = discover(packets, &CaptureOptions::default());
+
+        assert_eq!(channels.video.map(|stream| stream.source_port()), Some(50000));
+        assert_eq!(channels.screenshare.map(|stream| stream.source_port()), Some(50003));
+    }
+
+    #[test]
+    fn discover_only_splits_out_a_screen_share_while_both_are_sending() {
+        let start = Instant::now();
+        // The second video sized stream starts well after the first stopped, as when a call moves ports
+        let second_from = 40 * 20 + SCREENSHARE_OVERLAP_MSEC as u64 + 1000;
+        let packets = (0..40).map(|i| udp(start, 50000, 1000, i * 20))
+            .chain((0..40).map(|i| udp(start, 50003, 1300, second_from + i * 20)))
+            .collect();
+        let channels = discover(packets, &CaptureOptions::default());
+
+        assert_eq!(channels.video.map(|stream| stream.source_port()), Some(50003));
+        assert_eq!(channels.screenshare, None);
+    }
+
+    #[test]
+    fn discover_keeps_control_on_its_port_while_it_is_active() {
+        let start = Instant::now();
+        // Another small stream turns up once the control port's been found, and both carry on
+        let packets = (0..40).flat_map(|i| {
+            let mut packets = vec![udp(start, 50002, 60, i * 20)];
+            if i >= 15 {
+                packets.push(udp(start, 50005, 70, i * 20 + 1));
+            }
+            packets
+        }).collect();
+        let channels = discover(packets, &CaptureOptions::default());
+
+        assert_eq!(channels.control.map(|stream| stream.source_port()), Some(50002));
+        assert_eq!(channels.reassignments, 0);
+    }
+
+    #[test]
+    fn discover_holds_a_stream_back_during_the_cooldown() {
+        let start = Instant::now();
+        // Just over the video threshold, but not by the margin needed to move during the cooldown
+        let packets = (0..40).map(|i| udp(start, 50000, if i < 15 { 200 } else { 600 }, i * 20)).collect();
+        let options = CaptureOptions { reclassify_cooldown_ms: 60_000, ..CaptureOptions::default() };
+        let channels = discover(packets, &options);
+
+        assert_eq!(channels.audio.map(|stream| stream.source_port()), Some(50000));
+        assert_eq!(channels.video, None);
+        assert_eq!(channels.reassignments, 0);
+    }
+
+    #[test]
+    fn size_buckets_parse_largest_first() {
+        assert_eq!(SizeBucket::parse_list("0:control, 500:video,90:audio"), Ok(SizeBucket::defaults()));
+        assert_eq!(SizeBucket::parse_list("700:video,0:audio"), Ok(vec![
+            SizeBucket {above: 700, kind: ChannelKind::Video},
+            SizeBucket {above: 0, kind: ChannelKind::Audio}
+        ]));
+    }
+
+    #[test]
+    fn size_buckets_reject_bad_lists() {
+        assert!(SizeBucket::parse_list("90:video,500:audio").is_err());
+        assert!(SizeBucket::parse_list("500:video,400:video").is_err());
+        assert!(SizeBucket::parse_list("500:screen").is_err());
+        assert!(SizeBucket::parse_list("lots:video").is_err());
+        assert!(SizeBucket::parse_list("500").is_err());
+        assert!(SizeBucket::with_thresholds(Some(80), None).is_err());
+        assert_eq!(SizeBucket::with_thresholds(Some(700), None).unwrap()[0], SizeBucket {above: 700, kind: ChannelKind::Video});
+    }
+
+    #[test]
+    fn calibrate_splits_sizes_at_the_biggest_gaps() {
+        assert_eq!(SizeBucket::calibrate(&[1000, 60, 950, 210, 180, 70]), Some(vec![
+            SizeBucket {above: 580, kind: ChannelKind::Video},
+            SizeBucket {above: 125, kind: ChannelKind::Audio},
+            SizeBucket {above: 0, kind: ChannelKind::Control}
+        ]));
+    }
+
+    #[test]
+    fn calibrate_needs_three_different_sizes() {
+        assert_eq!(SizeBucket::calibrate(&[]), None);
+        assert_eq!(SizeBucket::calibrate(&[1000, 200, 1000, 200]), None);
+    }
+
+    #[test]
+    fn port_ranges_parse_single_ports_ranges_and_lists() {
+        assert_eq!("8801".parse(), Ok(PortRange {start: 8801, end: 8801}));
+        assert_eq!("8801-8810".parse(), Ok(PortRange {start: 8801, end: 8810}));
+        assert_eq!(PortRange::parse_list("8801, 9000-9001"), Ok(vec![
+            PortRange {start: 8801, end: 8801},
+            PortRange {start: 9000, end: 9001}
+        ]));
+    }
+
+    #[test]
+    fn port_ranges_reject_bad_ports() {
+        assert!("8810-8801".parse::<PortRange>().is_err());
+        assert!("0".parse::<PortRange>().is_err());
+        assert!("65536".parse::<PortRange>().is_err());
+        assert!("zoom".parse::<PortRange>().is_err());
+        assert!(PortRange::parse_list("8801,").is_err());
+    }
+
+    #[test]
+    fn destination_filter_covers_every_range() {
+        assert_eq!(destination_filter(&[PortRange {start: 8801, end: 8801}]), "dst port 8801");
+        assert_eq!(destination_filter(&[PortRange {start: 8801, end: 8801}, PortRange {start: 9000, end: 9010}]),
+            "(dst port 8801 || dst portrange 9000-9010)");
+    }
+
+    #[test]
+    fn crosses_by_margin_needs_to_be_well_past_the_threshold() {
+        let buckets = SizeBucket::defaults();
+        let (audio, video) = (Some(ChannelKind::Audio), Some(ChannelKind::Video));
+
+        // Moving up from audio means going 50% over the video threshold of 500
+        assert!(!crosses_by_margin(750, audio, video, &buckets));
+        assert!(crosses_by_margin(751, audio, video, &buckets));
+        // Moving down from video means dropping 50% under it
+        assert!(!crosses_by_margin(250, video, audio, &buckets));
+        assert!(crosses_by_margin(249, video, audio, &buckets));
+        assert!(crosses_by_margin(100, None, audio, &buckets));
+    }
+
+    #[test]
+    fn safe_snaplen_covers_the_headers_on_each_link_type() {
+        // Ethernet with a VLAN tag, IPv6 and UDP, plus the start of a STUN message
+        assert!(safe_snaplen(Linktype(1)) >= 14 + 4 + 40 + 8 + STUN_PREFIX_LEN);
+        // And with two VLAN tags and an IPv4 header full of options
+        assert!(safe_snaplen(Linktype(1)) >= 14 + 8 + 60 + 8 + STUN_PREFIX_LEN);
+
+        assert_eq!(link_header_len(Linktype(113)), 16);
+        assert_eq!(link_header_len(Linktype(276)), 20);
+        assert_eq!(link_header_len(Linktype(0)), 4);
+        assert_eq!(link_header_len(Linktype(101)), 0);
+        assert_eq!(safe_snaplen(Linktype(101)), MAX_IP_HEADER_LEN + UDP_HEADER_LEN + STUN_PREFIX_LEN);
+        assert!(link_header_len(Linktype(9999)) >= link_header_len(Linktype(276)));
+    }
+
+    #[test]
+    fn is_stun_looks_for_the_magic_cookie() {
+        assert!(is_stun(&[0x00, 0x01, 0x00, 0x00, 0x21, 0x12, 0xA4, 0x42, 0xFF]));
+        // Media packets set the top bits, which STUN never does
+        assert!(!is_stun(&[0x80, 0x01, 0x00, 0x00, 0x21, 0x12, 0xA4, 0x42]));
+        assert!(!is_stun(&[0x00, 0x01, 0x00, 0x00, 0x21, 0x12, 0xA4, 0x43]));
+        assert!(!is_stun(&[0x00, 0x01, 0x00, 0x00, 0x21, 0x12, 0xA4]));
+    }
+
+    /// An IPv4 packet carrying a UDP datagram with `payload_len` bytes of payload, from `port` to the server
+    fn ipv4_udp(port: u16, payload_len: u16) -> Vec<u8> {
+        let udp_len = 8 + payload_len;
+        let mut packet = vec![0x45, 0x00];
+        packet.extend((20 + udp_len).to_be_bytes());
+        // Identification, fragment offset, TTL, protocol (UDP) and an unchecked checksum
+        packet.extend([0x00, 0x00, 0x00, 0x00, 64, 17, 0x00, 0x00]);
+        packet.extend([192, 168, 1, 2, 10, 0, 0, 1]);
+        packet.extend(udp_header(port, udp_len));
+        packet.resize(packet.len() + usize::from(payload_len), 0);
+        packet
+    }
+
+    /// A UDP header from `port` to Zoom's port, without a checksum
+    fn udp_header(port: u16, udp_len: u16) -> Vec<u8> {
+        [port.to_be_bytes(), ZOOM_PORT.to_be_bytes(), udp_len.to_be_bytes(), [0x00, 0x00]].concat()
+    }
+
+    /// Wrap an IP packet in an Ethernet frame with the given EtherType
+    fn ethernet(ether_type: u16, packet: &[u8]) -> Vec<u8> {
+        [&[0x02, 0x00, 0x00, 0x00, 0x00, 0x01, 0x02, 0x00, 0x00, 0x00, 0x00, 0x02], &ether_type.to_be_bytes()[..], packet]
+            .concat()
+    }
+
+    /// Unpack a frame as if it was captured on a link of this type
+    fn unpack(frame: &[u8], linktype: i32) -> NextPacket {
+        unpack_packet(frame, Linktype(linktype), Utc.timestamp_opt(1_600_000_000, 0).unwrap())
+    }
+
+    /// The source port, UDP length and destination of a decoded packet
+    fn port_length_destination(packet: NextPacket) -> Option<(u16, u16, IpAddr)> {
+        match packet {
+            NextPacket::Udp(port, length, destination, _) => Some((port, length, destination)),
+            _ => None
+        }
+    }
+
+    #[test]
+    fn unpack_packet_skips_the_link_header_for_each_link_type() {
+        let packet = ipv4_udp(50000, 100);
+        let expected = Some((50000, 108, SERVER));
+
+        assert_eq!(port_length_destination(unpack(&ethernet(0x0800, &packet), 1)), expected);
+        // Linux cooked v1 ends with the protocol, and v2 starts with it
+        let sll = [&[0x00, 0x04, 0x00, 0x01, 0x00, 0x06, 0x02, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x08, 0x00][..],
+            &packet].concat();
+        assert_eq!(port_length_destination(unpack(&sll, 113)), expected);
+        let sll2 = [&[0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x01, 0x04, 0x06,
+            0x02, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00][..], &packet].concat();
+        assert_eq!(port_length_destination(unpack(&sll2, 276)), expected);
+        // BSD loopback starts with the address family in host byte order
+        let loopback = [&2u32.to_ne_bytes()[..], &packet].concat();
+        assert_eq!(port_length_destination(unpack(&loopback, 0)), expected);
+        assert_eq!(port_length_destination(unpack(&packet, 101)), expected);
+        assert_eq!(port_length_destination(unpack(&packet, 12)), expected);
+    }
+
+    #[test]
+    fn unpack_packet_reads_ipv6() {
+        let (client, server) = (Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2), Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+        let mut packet = vec![0x60, 0x00, 0x00, 0x00];
+        packet.extend(108u16.to_be_bytes());
+        // Next header (UDP) and hop limit
+        packet.extend([17, 64]);
+        packet.extend(client.octets());
+        packet.extend(server.octets());
+        packet.extend(udp_header(50001, 108));
+        packet.resize(packet.len() + 100, 0);
+
+        let expected = Some((50001, 108, IpAddr::V6(server)));
+        assert_eq!(port_length_destination(unpack(&ethernet(0x86DD, &packet), 1)), expected);
+        assert_eq!(port_length_destination(unpack(&packet, 101)), expected);
+    }
+
+    #[test]
+    fn unpack_packet_reads_vlan_tagged_frames() {
+        // VLAN 100, then the EtherType of what's inside
+        let tagged = [&[0x00, 0x64, 0x08, 0x00][..], &ipv4_udp(50002, 40)].concat();
+
+        assert_eq!(port_length_destination(unpack(&ethernet(0x8100, &tagged), 1)), Some((50002, 48, SERVER)));
+        // And double tagged (QinQ)
+        let double_tagged = [&[0x00, 0xC8, 0x81, 0x00][..], &tagged].concat();
+        assert_eq!(port_length_destination(unpack(&ethernet(0x88A8, &double_tagged), 1)), Some((50002, 48, SERVER)));
+    }
+
+    #[test]
+    fn unpack_packet_rejects_frames_it_cannot_slice() {
+        let packet = ipv4_udp(50000, 100);
+
+        // A raw IP packet read as Ethernet, or a truncated cooked header
+        assert_eq!(unpack(&packet, 1), NextPacket::Undecodable);
+        assert_eq!(unpack(&packet[..10], 113), NextPacket::Undecodable);
+    }
+
+    #[test]
+    fn discover_calibrates_once_the_call_has_settled() {
+        let start = Instant::now();
+        let options = CaptureOptions { calibrate: true, ..CaptureOptions::default() };
+
+        let channels = discover(call_packets(start, 200), &options);
+        assert!(!channels.calibration_done);
+
+        let channels = discover(call_packets(start, 300), &options);
+        assert!(channels.calibration_done);
+        // Halfway between the streams' averages, which are still creeping up towards 1000, 200 and 60 bytes
+        let buckets = channels.calibrated_buckets.unwrap();
+        assert_eq!(buckets.iter().map(|bucket| bucket.kind).collect::<Vec<ChannelKind>>(),
+            vec![ChannelKind::Video, ChannelKind::Audio, ChannelKind::Control]);
+        assert!((590..=600).contains(&buckets[0].above), "video above {}", buckets[0].above);
+        assert!((120..=130).contains(&buckets[1].above), "audio above {}", buckets[1].above);
+    }
+
+    #[test]
+    fn discover_gives_up_calibrating_without_three_sizes() {
+        let start = Instant::now();
+        let options = CaptureOptions { calibrate: true, ..CaptureOptions::default() };
+        let packets = (0..350).flat_map(|i| vec![udp(start, 50000, 1000, i * 100), udp(start, 50001, 200, i * 100 + 1)]).collect();
+
+        let channels = discover(packets, &options);
+        assert!(channels.calibration_done);
+        assert_eq!(channels.calibrated_buckets, None);
+    }
+
+    #[test]
+    fn discover_only_calibrates_once() {
+        let channel_map = RwLock::new(zoom_channels::ZoomChannels { calibration_done: true, ..zoom_channels::ZoomChannels::new() });
+        let options = CaptureOptions { calibrate: true, ..CaptureOptions::default() };
+        PortDiscoveryCapture::discover(&mut VecPacketSource::new(call_packets(Instant::now(), 300)), &options, &channel_map,
+            &SimpleAtomicBool::new(false)).unwrap();
+
+        assert_eq!(channel_map.into_inner().unwrap().calibrated_buckets, None);
+    }
+
+    /// Yields its packets, then fails as a live capture does when its device goes away
+    struct FailingSource(VecPacketSource);
+
+    impl PacketSource for FailingSource {
+        fn next_packet(&mut self) -> Result<NextPacket, ZoomTallyError> {
+            self.0.next_packet().map_err(|_| ZoomTallyError::Capture(pcap::Error::PcapError("The interface went down".to_string())))
+        }
+
+        fn stats(&mut self) -> Result<pcap::Stat, ZoomTallyError> {
+            self.0.stats()
+        }
+    }
+
+    /// Yields its packets, but after `poison_after` of them the consumer of the channel map panics while writing to it,
+    /// as the main thread does if it crashes
+    struct PoisoningSource<'a> {
+        packets: VecPacketSource,
+        channel_map: &'a RwLock<zoom_channels::ZoomChannels>,
+        poison_after: usize,
+        read: usize
+    }
+
+    impl PacketSource for PoisoningSource<'_> {
+        fn next_packet(&mut self) -> Result<NextPacket, ZoomTallyError> {
+            if self.read == self.poison_after {
+                std::thread::scope(|scope| {
+                    let crashed = scope.spawn(|| {
+                        let _write_map = self.channel_map.write();
+                        panic!("the main thread went away");
+                    }).join();
+                    assert!(crashed.is_err());
+                });
+            }
+            self.read += 1;
+            self.packets.next_packet()
+        }
+
+        fn stats(&mut self) -> Result<pcap::Stat, ZoomTallyError> {
+            self.packets.stats()
+        }
+    }
+
+    #[test]
+    fn discover_stops_cleanly_at_the_end_of_the_packets() {
+        let channel_map = RwLock::new(zoom_channels::ZoomChannels::new());
+        let result = PortDiscoveryCapture::discover(&mut VecPacketSource::new(call_packets(Instant::now(), 5)),
+            &CaptureOptions::default(), &channel_map, &SimpleAtomicBool::new(false));
+
+        assert!(result.is_ok());
+        assert_eq!(channel_map.into_inner().unwrap().packets_processed, 15);
+    }
+
+    #[test]
+    fn discover_stops_once_the_channel_map_is_poisoned() {
+        let channel_map = RwLock::new(zoom_channels::ZoomChannels::new());
+        let mut source = PoisoningSource {
+            packets: VecPacketSource::new(call_packets(Instant::now(), 20)),
+            channel_map: &channel_map,
+            poison_after: 10,
+            read: 0
+        };
+
+        let result = PortDiscoveryCapture::discover(&mut source, &CaptureOptions::default(), &channel_map,
+            &SimpleAtomicBool::new(false));
+
+        // The packet read as the lock was poisoned is the last
+        assert!(result.is_ok());
+        assert_eq!(source.read, 11);
+        assert!(channel_map.is_poisoned());
+    }
+
+    #[test]
+    fn discover_and_monitor_pass_on_capture_failures() {
+        let start = Instant::now();
+        let channel_map = RwLock::new(zoom_channels::ZoomChannels::new());
+        let result = PortDiscoveryCapture::discover(&mut FailingSource(VecPacketSource::new(call_packets(start, 20))),
+            &CaptureOptions::default(), &channel_map, &SimpleAtomicBool::new(false));
+        assert!(matches!(result, Err(ZoomTallyError::Capture(pcap::Error::PcapError(_)))));
+
+        let result = PortMonitorCapture::monitor(&mut FailingSource(VecPacketSource::new(call_packets(start, 5))),
+            &CaptureOptions::default(), &channel_map, &SimpleAtomicBool::new(false));
+        assert!(matches!(result, Err(ZoomTallyError::Capture(pcap::Error::PcapError(_)))));
+    }
+
+    #[test]
+    fn monitor_keeps_assigned_streams_in_their_channel() {
+        let start = Instant::now();
+        let channel_map = RwLock::new(discover(call_packets(start, 20), &CaptureOptions::default()));
+
+        // The camera's gone quiet, so the video stream's packets shrink into the audio range
+        let packets = (20..60).map(|i| udp(start, 50000, 300, i * 20)).collect();
+        PortMonitorCapture::monitor(&mut VecPacketSource::new(packets), &CaptureOptions::default(), &channel_map,
+            &SimpleAtomicBool::new(false)).unwrap();
+        let channels = channel_map.into_inner().unwrap();
+
+        let video = channels.video.unwrap();
+        assert_eq!(video.source_port(), 50000);
+        assert!(video.average_packet_size() < VIDEO_ABOVE);
+        assert_eq!(video.last_seen().seen_at, start + std::time::Duration::from_millis(59 * 20));
+        assert_eq!(channels.audio.map(|stream| stream.source_port()), Some(50001));
+    }
+
+    #[test]
+    fn monitor_updates_a_stream_in_the_channel_it_moved_to() {
+        let start = Instant::now();
+        // The port started out as video, then dropped to audio before monitoring started
+        let packets = (0..60).map(|i| udp(start, 50000, if i < 15 { 1200 } else { 200 }, i * 20)).collect();
+        let channel_map = RwLock::new(discover(packets, &CaptureOptions::default()));
+
+        let packets = (60..100).map(|i| udp(start, 50000, 200, i * 20)).collect();
+        PortMonitorCapture::monitor(&mut VecPacketSource::new(packets), &CaptureOptions::default(), &channel_map,
+            &SimpleAtomicBool::new(false)).unwrap();
+        let channels = channel_map.into_inner().unwrap();
+
+        assert_eq!(channels.video, None);
+        let audio = channels.audio.unwrap();
+        assert_eq!(audio.source_port(), 50000);
+        assert_eq!(audio.last_seen().seen_at, start + std::time::Duration::from_millis(99 * 20));
+        assert_eq!(channels.reassignments, 1);
+    }
+
+    #[test]
+    fn monitor_follows_a_stream_discovery_only_just_established() {
+        let start = Instant::now();
+        let channel_map = RwLock::new(discover(call_packets(start, 20), &CaptureOptions::default()));
+
+        // The call drops and reconnects from a new video port. Rediscovery only sees enough of it to fill the window,
+        // and a new audio port hasn't even got that far.
+        channel_map.write().unwrap().forget_idle_streams(start + std::time::Duration::from_secs(10), Duration::seconds(5));
+        let reconnected = (0..BITRATE_WINDOW_SIZE.into()).flat_map(|i| {
+            let at_ms = 10_000 + i * 20;
+            let audio = (i < 5).then(|| udp(start, 50011, 200, at_ms));
+            std::iter::once(udp(start, 50010, 1200, at_ms)).chain(audio)
+        }).collect();
+        PortDiscoveryCapture::discover(&mut VecPacketSource::new(reconnected), &CaptureOptions::default(), &channel_map,
+            &SimpleAtomicBool::new(false)).unwrap();
+        assert_eq!(channel_map.read().unwrap().video.map(|stream| stream.source_port()), Some(50010));
+
+        let packets = (0..20).flat_map(|i| {
+            let at_ms = 11_000 + i * 20;
+            [udp(start, 50010, 1000, at_ms), udp(start, 50011, 200, at_ms)]
+        }).collect();
+        PortMonitorCapture::monitor(&mut VecPacketSource::new(packets), &CaptureOptions::default(), &channel_map,
+            &SimpleAtomicBool::new(false)).unwrap();
+        let channels = channel_map.into_inner().unwrap();
+
+        let video = channels.video.unwrap();
+        assert_eq!(video.source_port(), 50010);
+        assert!(video.average_packet_size() < 1200);
+        assert_eq!(video.last_seen().seen_at, start + std::time::Duration::from_millis(11_000 + 19 * 20));
+        // The half-filled audio port isn't followed, so it's left for the next rediscovery rather than guessed at
+        assert_eq!(channels.streams[&(50011, None)].window_fill(), 5);
+        assert_ne!(channels.audio.map(|stream| stream.source_port()), Some(50011));
     }
 }
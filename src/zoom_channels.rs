@@ -1,9 +1,1309 @@
-use crate::stream_analyser;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::time::Instant;
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::stream_analyser::{self, ChannelKind, elapsed_since};
+
+/// Default for the time since the last packet after which a video or audio channel is considered off
+const DEFAULT_AV_CHANNEL_OFF_MSEC: i64 = 200;
+
+/// Default for the time since the last packet on any channel after which the call is considered to have ended
+const DEFAULT_CALL_TIMEOUT_MSEC: i64 = 3000;
+
+/// How long the audio channel must have been around with no video before we treat the call as audio-only
+const AUDIO_ONLY_SETTLE_MSEC: i64 = 5000;
+
+/// Default for how long a video bitrate step must last before it's reported
+const DEFAULT_VIDEO_STEP_CONFIRM_MSEC: i64 = 3000;
+
+/// Default for how long the call must stay silent past the timeout before it's reported as off
+const DEFAULT_CALL_LINGER_MSEC: i64 = 1000;
+
+/// Default for how many updates in a row a channel must look off before it's reported off
+const DEFAULT_OFF_DEBOUNCE: u32 = 3;
 
 /// Represents the streams known of the video, audio and control ports
 #[derive(Hash, Eq, PartialEq, Debug, Clone)]
 pub struct ZoomChannels {
     pub video: Option<stream_analyser::PacketStream>,
     pub audio: Option<stream_analyser::PacketStream>,
-    pub control: Option<stream_analyser::PacketStream>
+    pub control: Option<stream_analyser::PacketStream>,
+    /// A second video sized stream sent alongside the video, while the screen's being shared
+    pub screenshare: Option<stream_analyser::PacketStream>,
+    /// When the capture thread last went round its loop, whether or not it saw a packet
+    pub last_capture_cycle: Instant,
+    /// How many packets the capture thread has processed
+    pub packets_processed: u64,
+    /// How many captured packets couldn't be decoded as UDP, and were skipped
+    pub undecodable_packets: u64,
+    /// How many STUN negotiation packets were seen, and left out of classification
+    pub stun_packets: u64,
+    /// How many times a port has been moved from one channel to another
+    pub reassignments: u64,
+    /// How many distinct streams discovery has seen: source ports, or source port and destination pairs if streams
+    /// are told apart by destination
+    pub ports_seen: usize,
+    /// How many of the ports seen currently fall into each size tier
+    pub ports_by_tier: PortTiers,
+    /// Latest copy of every stream the capture thread is tracking, classified or not
+    pub streams: BTreeMap<stream_analyser::StreamKey, stream_analyser::PacketStream>,
+    /// Size buckets worked out by calibration, once it's finished
+    pub calibrated_buckets: Option<Vec<stream_analyser::SizeBucket>>,
+    /// Whether calibration has finished, whether or not it managed to work out new size buckets
+    pub calibration_done: bool
+}
+
+/// Count of ports whose average packet size falls into each tier, whether or not they were picked for the channel
+#[derive(Hash, Eq, PartialEq, Debug, Clone, Copy, Default)]
+pub struct PortTiers {
+    pub video: usize,
+    pub audio: usize,
+    pub control: usize,
+    /// Ports too small for any tier
+    pub none: usize
+}
+
+impl PortTiers {
+    /// Count a port into a tier, or `None` if it didn't fit one
+    pub fn add(&mut self, tier: Option<ChannelKind>) {
+        match tier {
+            Some(ChannelKind::Video) => self.video += 1,
+            Some(ChannelKind::Audio) => self.audio += 1,
+            Some(ChannelKind::Control) => self.control += 1,
+            None => self.none += 1
+        }
+    }
+}
+
+impl Default for ZoomChannels {
+    fn default() -> Self {
+        ZoomChannels::new()
+    }
+}
+
+impl ZoomChannels {
+    pub fn new() -> ZoomChannels {
+        ZoomChannels {
+            video: None,
+            audio: None,
+            control: None,
+            screenshare: None,
+            last_capture_cycle: Instant::now(),
+            packets_processed: 0,
+            undecodable_packets: 0,
+            stun_packets: 0,
+            reassignments: 0,
+            ports_seen: 0,
+            ports_by_tier: PortTiers::default(),
+            streams: BTreeMap::new(),
+            calibrated_buckets: None,
+            calibration_done: false
+        }
+    }
+
+    /// Which channel a stream is currently assigned to, if any
+    pub fn channel_for_stream(&self, stream: &stream_analyser::PacketStream) -> Option<ChannelKind> {
+        // A screen share is in the video size tier, it's just the second stream there
+        [(self.video, ChannelKind::Video), (self.audio, ChannelKind::Audio), (self.control, ChannelKind::Control),
+            (self.screenshare, ChannelKind::Video)].iter()
+            .find(|(channel, _)| channel.is_some_and(|channel| channel.is_same_stream(stream)))
+            .map(|(_, kind)| *kind)
+    }
+
+    /// Forget the streams that haven't sent anything for a while, e.g. from earlier calls, so monitoring doesn't keep
+    /// watching every port ever seen
+    ///
+    /// The channels keep their streams, so their statuses carry on being reported.
+    pub fn forget_idle_streams(&mut self, now: Instant, idle_after: Duration) {
+        self.streams.retain(|_, stream| elapsed_since(now, stream.last_seen().seen_at) <= idle_after);
+    }
+}
+
+/// Whether a single channel is currently active
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZoomChannelStatus {
+    /// No packets have been processed yet, so we're still listening
+    Starting,
+    On,
+    Off,
+    /// Packets are arriving but we don't know which port this channel is on
+    Unknown
+}
+
+impl ZoomChannelStatus {
+    /// Lowercase name of the status, as used in output
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ZoomChannelStatus::Starting => "starting",
+            ZoomChannelStatus::On => "on",
+            ZoomChannelStatus::Off => "off",
+            ZoomChannelStatus::Unknown => "unknown"
+        }
+    }
+
+    /// True if we know which port the channel is on, so it's either on or off
+    pub fn is_known(&self) -> bool {
+        matches!(self, ZoomChannelStatus::On | ZoomChannelStatus::Off)
+    }
+}
+
+/// Hold a channel on until it's looked off for `debounce` updates in a row
+///
+/// # Arguments
+/// * `reported` - Status reported at the last update
+/// * `status` - Status the channel's stream has now
+/// * `off_updates` - How many updates in a row it's looked off while reported on, updated here
+/// * `debounce` - How many updates it must look off for
+fn debounce_off(reported: ZoomChannelStatus, status: ZoomChannelStatus, off_updates: &mut u32, debounce: u32) -> ZoomChannelStatus {
+    if reported == ZoomChannelStatus::On && status == ZoomChannelStatus::Off {
+        *off_updates += 1;
+        if *off_updates < debounce {
+            return ZoomChannelStatus::On;
+        }
+    }
+    *off_updates = 0;
+    status
+}
+
+/// Work out whether a channel is on, given the stream for it (if known)
+fn get_channel_status(stream: Option<stream_analyser::PacketStream>, now: Instant, off_after: Duration) -> ZoomChannelStatus {
+    match stream {
+        Some(stream) => {
+            if elapsed_since(now, stream.last_seen().seen_at) > off_after {
+                ZoomChannelStatus::Off
+            } else {
+                ZoomChannelStatus::On
+            }
+        },
+        None => ZoomChannelStatus::Unknown
+    }
+}
+
+/// Coarse quality of the video being sent, judged from its bitrate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoQuality {
+    Hd,
+    Sd,
+    Low
+}
+
+impl VideoQuality {
+    /// Lowercase name of the quality, as used in output
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            VideoQuality::Hd => "hd",
+            VideoQuality::Sd => "sd",
+            VideoQuality::Low => "low"
+        }
+    }
+}
+
+impl FromStr for VideoQuality {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hd" => Ok(VideoQuality::Hd),
+            "sd" => Ok(VideoQuality::Sd),
+            "low" => Ok(VideoQuality::Low),
+            _ => Err(format!("Unknown video quality {:?}, expected hd, sd or low", s))
+        }
+    }
+}
+
+/// Video at more than `above_kbps` kilobits per second is of this `quality`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QualityBand {
+    pub above_kbps: u32,
+    pub quality: VideoQuality
+}
+
+impl QualityBand {
+    /// Roughly where Zoom's 720p and 360p streams sit
+    pub fn defaults() -> Vec<QualityBand> {
+        vec![
+            QualityBand {above_kbps: 1200, quality: VideoQuality::Hd},
+            QualityBand {above_kbps: 400, quality: VideoQuality::Sd},
+            QualityBand {above_kbps: 0, quality: VideoQuality::Low}
+        ]
+    }
+
+    /// Parse a comma separated list of bands like `1200:hd,400:sd,0:low`
+    ///
+    /// The bands are returned highest first, so the first one a bitrate is above is the one it belongs to.
+    pub fn parse_list(list: &str) -> Result<Vec<QualityBand>, String> {
+        let mut bands = list.split(',')
+            .map(|band| band.trim().parse())
+            .collect::<Result<Vec<QualityBand>, String>>()?;
+        bands.sort_by_key(|band| std::cmp::Reverse(band.above_kbps));
+
+        Ok(bands)
+    }
+
+    /// Quality of video at a bitrate, from the first band (highest first) it's above
+    ///
+    /// A bitrate exactly on a band's threshold falls into the band below, except that a band starting at 0 takes
+    /// everything, so a stalled stream still has a quality.
+    fn quality_for(bands: &[QualityBand], kbps: u32) -> Option<VideoQuality> {
+        bands.iter().find(|band| kbps > band.above_kbps || band.above_kbps == 0).map(|band| band.quality)
+    }
+}
+
+impl FromStr for QualityBand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (above, quality) = s.split_once(':').ok_or(format!("Quality band {:?} should look like 1200:hd", s))?;
+        let above_kbps = above.parse().map_err(|_| format!("Quality band threshold {:?} isn't a number of kbps", above))?;
+
+        Ok(QualityBand {above_kbps, quality: quality.parse()?})
+    }
+}
+
+/// A point on the curve mapping combined audio and video bitrate to an activity level
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ActivityPoint {
+    pub kbps: u32,
+    /// Activity level from 0 to 100
+    pub level: u8
+}
+
+impl ActivityPoint {
+    /// Idle, audio only, camera on and screen sharing
+    pub fn defaults() -> Vec<ActivityPoint> {
+        vec![
+            ActivityPoint {kbps: 0, level: 0},
+            ActivityPoint {kbps: 40, level: 30},
+            ActivityPoint {kbps: 1000, level: 80},
+            ActivityPoint {kbps: 2500, level: 100}
+        ]
+    }
+
+    /// Parse a comma separated list of points like `0:0,40:30,1000:80,2500:100`
+    ///
+    /// The points are returned lowest bitrate first.
+    pub fn parse_list(list: &str) -> Result<Vec<ActivityPoint>, String> {
+        let mut points = list.split(',')
+            .map(|point| point.trim().parse())
+            .collect::<Result<Vec<ActivityPoint>, String>>()?;
+        points.sort_by_key(|point| point.kbps);
+
+        Ok(points)
+    }
+
+    /// Activity level for a bitrate, interpolating between the points either side of it
+    ///
+    /// Bitrates below the first point or above the last get that point's level.
+    fn level_for(points: &[ActivityPoint], kbps: u32) -> u8 {
+        let above = points.iter().position(|point| point.kbps > kbps);
+        match above {
+            Some(0) => points[0].level,
+            Some(index) => {
+                let (low, high) = (points[index - 1], points[index]);
+                let fraction = f64::from(kbps - low.kbps) / f64::from(high.kbps - low.kbps);
+                (f64::from(low.level) + fraction * (f64::from(high.level) - f64::from(low.level))).round() as u8
+            },
+            None => points.last().map_or(0, |point| point.level)
+        }
+    }
+}
+
+impl FromStr for ActivityPoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kbps, level) = s.split_once(':').ok_or(format!("Activity point {:?} should look like 1000:80", s))?;
+        let kbps = kbps.parse().map_err(|_| format!("Activity point bitrate {:?} isn't a number of kbps", kbps))?;
+        let level = match level.parse() {
+            Ok(level) if level <= 100 => level,
+            _ => return Err(format!("Activity level {:?} should be from 0 to 100", level))
+        };
+
+        Ok(ActivityPoint {kbps, level})
+    }
+}
+
+/// Which capture process is running
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureMode {
+    /// Watching all Zoom traffic to work out which port is which
+    Discover,
+    /// Watching just the known audio and video ports
+    Monitor
+}
+
+impl CaptureMode {
+    /// Lowercase name of the mode, as used in output
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CaptureMode::Discover => "discover",
+            CaptureMode::Monitor => "monitor"
+        }
+    }
+}
+
+/// A change in the session worth reporting on its own, rather than as a status
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionEvent {
+    CallStarted,
+    CallEnded,
+    /// The audio channel stopped during a call
+    MicMuted,
+    /// The audio channel started again during a call
+    MicUnmuted,
+    /// The video bitrate stepped up or down and stayed there, e.g. from switching view or toggling HD
+    VideoBitrateChanged { from_kbps: u32, to_kbps: u32 }
+}
+
+impl SessionEvent {
+    /// Lowercase name of the event, as used in output
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SessionEvent::CallStarted => "call_started",
+            SessionEvent::CallEnded => "call_ended",
+            SessionEvent::MicMuted => "mic_muted",
+            SessionEvent::MicUnmuted => "mic_unmuted",
+            SessionEvent::VideoBitrateChanged { .. } => "video_bitrate_changed"
+        }
+    }
+}
+
+/// Totals over a whole run, for a report at the end
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionSummary {
+    /// Number of calls that started
+    pub calls: u32,
+    pub call_time: Duration,
+    pub camera_time: Duration,
+    pub mic_time: Duration,
+    /// Largest average packet size each channel's stream reached, in bytes
+    pub peak_video_packet_size: u16,
+    pub peak_audio_packet_size: u16,
+    pub peak_control_packet_size: u16
+}
+
+impl Default for SessionSummary {
+    fn default() -> Self {
+        SessionSummary::new()
+    }
+}
+
+impl SessionSummary {
+    pub fn new() -> SessionSummary {
+        SessionSummary {
+            calls: 0,
+            call_time: Duration::zero(),
+            camera_time: Duration::zero(),
+            mic_time: Duration::zero(),
+            peak_video_packet_size: 0,
+            peak_audio_packet_size: 0,
+            peak_control_packet_size: 0
+        }
+    }
+
+    /// Add the time between two updates of the session state, counting it against whatever was on at the earlier one
+    ///
+    /// # Arguments
+    /// * `previous` - State at the earlier update
+    /// * `elapsed` - Time since the earlier update
+    /// * `events` - Events between the two updates
+    pub fn record(&mut self, previous: &ZoomSessionState, elapsed: Duration, events: &[SessionEvent]) {
+        self.calls += events.iter().filter(|event| **event == SessionEvent::CallStarted).count() as u32;
+
+        if previous.is_in_meeting() {
+            self.call_time = self.call_time + elapsed;
+        }
+        if previous.camera_on() {
+            self.camera_time = self.camera_time + elapsed;
+        }
+        if previous.mic_on() {
+            self.mic_time = self.mic_time + elapsed;
+        }
+
+        // Averages from streams still settling don't mean much, so only established ones count towards the peaks
+        let peak_size = |peak: u16, stream: Option<stream_analyser::PacketStream>| {
+            stream.filter(|stream| stream.is_established()).map_or(peak, |stream| peak.max(stream.average_packet_size()))
+        };
+        self.peak_video_packet_size = peak_size(self.peak_video_packet_size, previous.channels.video);
+        self.peak_audio_packet_size = peak_size(self.peak_audio_packet_size, previous.channels.audio);
+        self.peak_control_packet_size = peak_size(self.peak_control_packet_size, previous.channels.control);
+    }
+}
+
+/// How long a channel spent in each status
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatusTimes {
+    pub starting: Duration,
+    pub on: Duration,
+    pub off: Duration,
+    pub unknown: Duration
+}
+
+impl Default for StatusTimes {
+    fn default() -> Self {
+        StatusTimes::new()
+    }
+}
+
+impl StatusTimes {
+    pub fn new() -> StatusTimes {
+        StatusTimes {
+            starting: Duration::zero(),
+            on: Duration::zero(),
+            off: Duration::zero(),
+            unknown: Duration::zero()
+        }
+    }
+
+    /// Count some time against a status
+    fn add(&mut self, status: ZoomChannelStatus, elapsed: Duration) {
+        let total = match status {
+            ZoomChannelStatus::Starting => &mut self.starting,
+            ZoomChannelStatus::On => &mut self.on,
+            ZoomChannelStatus::Off => &mut self.off,
+            ZoomChannelStatus::Unknown => &mut self.unknown
+        };
+        *total = *total + elapsed;
+    }
+}
+
+/// Time the call, video and audio spent in each status over a run, to measure how stable classification is
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatusProfile {
+    pub call: StatusTimes,
+    pub video: StatusTimes,
+    pub audio: StatusTimes
+}
+
+impl Default for StatusProfile {
+    fn default() -> Self {
+        StatusProfile::new()
+    }
+}
+
+impl StatusProfile {
+    pub fn new() -> StatusProfile {
+        StatusProfile {
+            call: StatusTimes::new(),
+            video: StatusTimes::new(),
+            audio: StatusTimes::new()
+        }
+    }
+
+    /// Add the time between two updates of the session state, counting it against the statuses at the earlier one
+    pub fn record(&mut self, previous: &ZoomSessionState, elapsed: Duration) {
+        self.call.add(previous.call, elapsed);
+        self.video.add(previous.video, elapsed);
+        self.audio.add(previous.audio, elapsed);
+    }
+}
+
+/// The state of a Zoom session, derived from the channels known
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZoomSessionState {
+    pub channels: ZoomChannels,
+    /// Capture process currently running
+    pub mode: CaptureMode,
+    /// On if any channel has seen traffic recently, i.e. we're in a call
+    pub call: ZoomChannelStatus,
+    /// What `call` would be without the linger and end-of-call grace holding it on, for debugging
+    pub call_raw: ZoomChannelStatus,
+    pub video: ZoomChannelStatus,
+    pub audio: ZoomChannelStatus,
+    /// What `video`, `audio` and `screenshare` would be without `off_debounce` holding them on, for debugging
+    pub video_raw: ZoomChannelStatus,
+    pub audio_raw: ZoomChannelStatus,
+    pub screenshare_raw: ZoomChannelStatus,
+    /// Whether the control (signalling) channel is still sending, which it does while muted with the camera off
+    pub control: ZoomChannelStatus,
+    /// Whether a screen share is being sent alongside the video
+    pub screenshare: ZoomChannelStatus,
+    /// True if the video channel is on and carrying camera frames, not just a placeholder stream
+    pub camera_live: bool,
+    /// Video bitrate the camera has to be sending above to count as live
+    pub camera_live_above_kbps: u32,
+    /// True while joining a call, when only the control channel is active and no audio or video has appeared yet. The
+    /// call isn't reported as on until they do, unless `use_control_for_call` is set.
+    pub connecting: bool,
+    /// Quality of the video being sent, if the camera's on and its bitrate is known yet
+    pub video_quality: Option<VideoQuality>,
+    /// Bitrate bands used to judge `video_quality`, highest first
+    pub quality_bands: Vec<QualityBand>,
+    /// Overall activity from 0 (idle) to 100 (screen sharing), from the audio and video being sent
+    pub activity_level: u8,
+    /// Curve mapping the combined audio and video bitrate to `activity_level`, lowest bitrate first
+    pub activity_points: Vec<ActivityPoint>,
+    /// How far, as a percentage, the video bitrate must move from where it settled to count as a step change, or
+    /// `None` not to look for steps
+    pub video_step_percent: Option<u32>,
+    /// How long the video bitrate must stay moved before the step is reported, so noise isn't
+    pub video_step_confirm_ms: i64,
+    /// Video bitrate before and after a step confirmed at the last update, if there was one
+    video_step: Option<(u32, u32)>,
+    /// Video bitrate the stream last settled at
+    video_settled_kbps: Option<u32>,
+    /// When the video bitrate moved away from where it settled, if it's away now
+    video_step_since: Option<Instant>,
+    /// Time since the last packet after which the video, audio or screen share is considered off
+    pub av_off_ms: i64,
+    /// Time since the last packet on any channel after which the call, or the control channel, is considered off
+    pub call_timeout_ms: i64,
+    /// How long the call must stay silent past the timeout before it's reported as off, so brief blips don't end it
+    pub call_linger_ms: i64,
+    /// How long after the last packet on any stream the call must wait before it's reported as off. Unlike the
+    /// timeout this counts streams that never got established, so a short exchange of teardown packets on a fresh port
+    /// after the media stops still holds the call on.
+    pub call_end_grace_ms: i64,
+    /// Follow only the control channel for the call status, rather than any channel
+    pub use_control_for_call: bool,
+    /// When the call last went silent while it was on, if it's silent now
+    call_silent_since: Option<Instant>,
+    /// How many updates in a row the video, audio or screen share must look off before it's reported off, so a
+    /// dropped packet doesn't make it flap. Turning back on is reported straight away.
+    pub off_debounce: u32,
+    /// How many updates in a row the video, audio and screen share have looked off while still reported on
+    off_updates: [u32; 3]
+}
+
+impl Default for ZoomSessionState {
+    fn default() -> Self {
+        ZoomSessionState::new()
+    }
+}
+
+impl ZoomSessionState {
+    pub fn new() -> ZoomSessionState {
+        ZoomSessionState {
+            channels: ZoomChannels::new(),
+            mode: CaptureMode::Discover,
+            call: ZoomChannelStatus::Starting,
+            call_raw: ZoomChannelStatus::Starting,
+            video: ZoomChannelStatus::Starting,
+            audio: ZoomChannelStatus::Starting,
+            video_raw: ZoomChannelStatus::Starting,
+            audio_raw: ZoomChannelStatus::Starting,
+            screenshare_raw: ZoomChannelStatus::Starting,
+            control: ZoomChannelStatus::Starting,
+            screenshare: ZoomChannelStatus::Starting,
+            camera_live: false,
+            camera_live_above_kbps: stream_analyser::CAMERA_LIVE_ABOVE_KBPS,
+            connecting: false,
+            video_quality: None,
+            quality_bands: QualityBand::defaults(),
+            activity_level: 0,
+            activity_points: ActivityPoint::defaults(),
+            video_step_percent: None,
+            video_step_confirm_ms: DEFAULT_VIDEO_STEP_CONFIRM_MSEC,
+            video_step: None,
+            video_settled_kbps: None,
+            video_step_since: None,
+            av_off_ms: DEFAULT_AV_CHANNEL_OFF_MSEC,
+            call_timeout_ms: DEFAULT_CALL_TIMEOUT_MSEC,
+            call_linger_ms: DEFAULT_CALL_LINGER_MSEC,
+            call_end_grace_ms: 0,
+            use_control_for_call: false,
+            call_silent_since: None,
+            off_debounce: DEFAULT_OFF_DEBOUNCE,
+            off_updates: [0; 3]
+        }
+    }
+
+    /// Take a copy of the latest channels and recalculate the status of each
+    ///
+    /// # Arguments
+    /// * `channels` - Latest streams known from the capture thread
+    /// * `now` - Monotonic time to compare the streams' last packets against
+    pub fn update_channels(&mut self, channels: &ZoomChannels, now: Instant) {
+        self.channels = channels.clone();
+
+        // Until the first packet turns up we can't say anything about the channels
+        if self.channels.packets_processed == 0 {
+            return;
+        }
+
+        let off_after = Duration::milliseconds(self.av_off_ms);
+        let call_timeout = Duration::milliseconds(self.call_timeout_ms);
+        self.video_raw = get_channel_status(self.channels.video, now, off_after);
+        self.audio_raw = get_channel_status(self.channels.audio, now, off_after);
+        self.screenshare_raw = get_channel_status(self.channels.screenshare, now, off_after);
+        let [video_off_updates, audio_off_updates, screenshare_off_updates] = &mut self.off_updates;
+        self.video = debounce_off(self.video, self.video_raw, video_off_updates, self.off_debounce);
+        self.audio = debounce_off(self.audio, self.audio_raw, audio_off_updates, self.off_debounce);
+        self.screenshare = debounce_off(self.screenshare, self.screenshare_raw, screenshare_off_updates, self.off_debounce);
+        // Control only sends the odd keepalive, so give it as long as the call
+        self.control = get_channel_status(self.channels.control, now, call_timeout);
+
+        // The call is on as long as any channel, including control keepalives, is still sending. Only established
+        // streams count, so a stray packet or two can't start a call.
+        let call_statuses = [self.channels.control, self.channels.audio, self.channels.video]
+            .map(|stream| stream.filter(|stream| stream.is_established()))
+            .map(|stream| get_channel_status(stream, now, call_timeout));
+        let [control, _, _] = call_statuses;
+        self.connecting = control == ZoomChannelStatus::On
+            && self.audio == ZoomChannelStatus::Unknown
+            && self.video == ZoomChannelStatus::Unknown;
+        let call = if self.use_control_for_call {
+            // The control channel's keepalives carry on for the whole meeting, whatever the camera and mic are doing
+            control
+        } else if self.connecting {
+            // Still joining, so not in the call yet
+            ZoomChannelStatus::Off
+        } else if call_statuses.contains(&ZoomChannelStatus::On) {
+            ZoomChannelStatus::On
+        } else if call_statuses.contains(&ZoomChannelStatus::Off) {
+            ZoomChannelStatus::Off
+        } else {
+            ZoomChannelStatus::Unknown
+        };
+        self.call_raw = call;
+
+        // Once the call is on, it has to stay silent for the linger time before we believe it's over, and the
+        // end-of-call grace has to have passed since the last packet of any stream, including teardown traffic too
+        // short-lived to be classified
+        if self.call == ZoomChannelStatus::On && call == ZoomChannelStatus::Off {
+            let silent_since = *self.call_silent_since.get_or_insert(now);
+            let last_packet_seen = [self.channels.control, self.channels.audio, self.channels.video].iter()
+                .flatten()
+                .chain(self.channels.streams.values())
+                .map(|stream| stream.last_seen().seen_at)
+                .max();
+            let grace_over = last_packet_seen.is_none_or(|last_seen| {
+                elapsed_since(now, last_seen) >= Duration::milliseconds(self.call_end_grace_ms)
+            });
+
+            if elapsed_since(now, silent_since) >= Duration::milliseconds(self.call_linger_ms) && grace_over {
+                self.call = call;
+                self.call_silent_since = None;
+            }
+        } else {
+            self.call = call;
+            self.call_silent_since = None;
+        }
+
+        self.camera_live = self.camera_on()
+            && self.channels.video
+                .and_then(|stream| stream.bitrate_kbps())
+                .is_some_and(|kbps| kbps > self.camera_live_above_kbps);
+
+        self.video_quality = self.channels.video
+            .filter(|_| self.camera_on())
+            .and_then(|stream| stream.bitrate_kbps())
+            .and_then(|kbps| QualityBand::quality_for(&self.quality_bands, kbps));
+
+        let active_kbps: u32 = [(self.channels.audio, self.mic_on()), (self.channels.video, self.camera_on())].iter()
+            .filter(|(_, on)| *on)
+            .filter_map(|(stream, _)| stream.and_then(|stream| stream.bitrate_kbps()))
+            .sum();
+        self.activity_level = ActivityPoint::level_for(&self.activity_points, active_kbps);
+
+        self.video_step = None;
+        if let Some(step_percent) = self.video_step_percent {
+            self.detect_video_step(step_percent, now);
+        }
+    }
+
+    /// Look for the video bitrate moving well away from where it settled and staying there
+    fn detect_video_step(&mut self, step_percent: u32, now: Instant) {
+        let kbps = match self.channels.video.filter(|_| self.camera_on()).and_then(|stream| stream.bitrate_kbps()) {
+            Some(kbps) => kbps,
+            None => {
+                // Start afresh when the camera comes back on
+                self.video_settled_kbps = None;
+                self.video_step_since = None;
+                return;
+            }
+        };
+
+        let settled_kbps = *self.video_settled_kbps.get_or_insert(kbps);
+        let moved = u64::from(kbps.abs_diff(settled_kbps)) * 100 > u64::from(settled_kbps) * u64::from(step_percent);
+        if !moved {
+            // Just noise, it came back
+            self.video_step_since = None;
+            return;
+        }
+
+        let step_since = *self.video_step_since.get_or_insert(now);
+        if elapsed_since(now, step_since) >= Duration::milliseconds(self.video_step_confirm_ms) {
+            self.video_step = Some((settled_kbps, kbps));
+            self.video_settled_kbps = Some(kbps);
+            self.video_step_since = None;
+        }
+    }
+
+    /// True if we're in a Zoom meeting
+    pub fn is_in_meeting(&self) -> bool {
+        self.call == ZoomChannelStatus::On
+    }
+
+    /// True if the camera's on, i.e. the video channel is sending
+    ///
+    /// See `camera_live` to also rule out the placeholder stream Zoom sends while the camera's off.
+    pub fn camera_on(&self) -> bool {
+        self.video == ZoomChannelStatus::On
+    }
+
+    /// True if the mic's open, i.e. the audio channel is sending
+    pub fn mic_on(&self) -> bool {
+        self.audio == ZoomChannelStatus::On
+    }
+
+    /// True if the mic's muted in a call that's still going, as opposed to there being no call at all
+    pub fn mic_muted(&self) -> bool {
+        self.is_in_meeting() && self.audio == ZoomChannelStatus::Off
+    }
+
+    /// True if the camera's off in a call that's still going, as opposed to there being no call at all
+    pub fn camera_off(&self) -> bool {
+        self.is_in_meeting() && self.video == ZoomChannelStatus::Off
+    }
+
+    /// True once the channels in the call have been found, so discovery can stop and monitoring begin
+    ///
+    /// That's normally when both audio and video are known, but a call without a camera never has video, so audio on
+    /// its own is enough once it's been known for a while without video turning up.
+    pub fn channels_settled(&self, now: Instant) -> bool {
+        if self.audio.is_known() && self.video.is_known() {
+            return true;
+        }
+
+        self.audio.is_known()
+            && self.channels.audio.is_some_and(|audio| {
+                elapsed_since(now, audio.first_seen().seen_at) >= Duration::milliseconds(AUDIO_ONLY_SETTLE_MSEC)
+            })
+    }
+
+    /// True if discovery has found what it needs to and it's worth switching to monitor mode: we're discovering, in a
+    /// call, and its channels have settled
+    pub fn ready_to_monitor(&self, now: Instant) -> bool {
+        self.mode == CaptureMode::Discover && self.is_in_meeting() && self.channels_settled(now)
+    }
+
+    /// Events that happened between an earlier state and this one
+    ///
+    /// Mic mutes and unmutes are only reported while the call stays on, so the audio starting and stopping as the
+    /// call begins and ends doesn't count.
+    pub fn events_since(&self, previous: &ZoomSessionState) -> Vec<SessionEvent> {
+        let mut events = Vec::new();
+
+        if !previous.is_in_meeting() && self.is_in_meeting() {
+            events.push(SessionEvent::CallStarted);
+        } else if previous.is_in_meeting() && !self.is_in_meeting() {
+            events.push(SessionEvent::CallEnded);
+        }
+
+        if let Some((from_kbps, to_kbps)) = self.video_step {
+            events.push(SessionEvent::VideoBitrateChanged { from_kbps, to_kbps });
+        }
+
+        if previous.is_in_meeting() && self.is_in_meeting() {
+            match (previous.audio, self.audio) {
+                (ZoomChannelStatus::On, ZoomChannelStatus::Off) => events.push(SessionEvent::MicMuted),
+                (ZoomChannelStatus::Off, ZoomChannelStatus::On) => events.push(SessionEvent::MicUnmuted),
+                _ => ()
+            }
+        }
+
+        events
+    }
+
+    /// When the call started, taken as when the first of its channels was seen
+    pub fn call_started_at(&self) -> Option<DateTime<Utc>> {
+        [self.channels.control, self.channels.audio, self.channels.video].iter()
+            .flatten()
+            .map(|stream| stream.first_seen().captured_at)
+            .min()
+    }
+
+    /// How many milliseconds after the call started each of the control, audio and video channels was first seen
+    pub fn first_seen_offsets(&self) -> [Option<i64>; 3] {
+        let call_started_at = self.call_started_at();
+        [self.channels.control, self.channels.audio, self.channels.video].map(|stream| {
+            stream.zip(call_started_at).map(|(stream, started)| (stream.first_seen().captured_at - started).num_milliseconds())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::RwLock;
+
+    use chrono::TimeZone;
+    use stoppable_thread::SimpleAtomicBool;
+
+    use crate::stream_analyser::{CaptureOptions, NextPacket, PacketTime, PortDiscoveryCapture, VecPacketSource};
+
+    const VIDEO_PORT: u16 = 50000;
+    const AUDIO_PORT: u16 = 50001;
+    const CONTROL_PORT: u16 = 50002;
+
+    /// A UDP packet from a port, `at_ms` after `start`
+    fn udp(start: Instant, port: u16, length: u16, at_ms: u64) -> NextPacket {
+        let offset = std::time::Duration::from_millis(at_ms);
+        let time = PacketTime {
+            captured_at: Utc.timestamp_opt(1_600_000_000, 0).unwrap() + Duration::from_std(offset).unwrap(),
+            seen_at: start + offset
+        };
+        NextPacket::Udp(port, length, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), time)
+    }
+
+    /// Run discovery over packets from the given ports, every 20ms from `from_ms` until `to_ms`
+    fn discover(start: Instant, streams: &[(u16, u16)], from_ms: u64, to_ms: u64) -> ZoomChannels {
+        discover_every(start, streams, from_ms, to_ms, 20)
+    }
+
+    /// Run discovery over packets from the given ports, every `every_ms` from `from_ms` until `to_ms`
+    fn discover_every(start: Instant, streams: &[(u16, u16)], from_ms: u64, to_ms: u64, every_ms: usize) -> ZoomChannels {
+        let packets = (from_ms..to_ms).step_by(every_ms)
+            .flat_map(|at_ms| streams.iter().map(move |(port, length)| udp(start, *port, *length, at_ms)))
+            .collect();
+        let channel_map = RwLock::new(ZoomChannels::new());
+        PortDiscoveryCapture::discover(&mut VecPacketSource::new(packets), &CaptureOptions::default(), &channel_map,
+            &SimpleAtomicBool::new(false)).unwrap();
+        channel_map.into_inner().unwrap()
+    }
+
+    /// A call with the camera, mic and control channel all sending for its first `to_ms`
+    fn full_call(start: Instant, to_ms: u64) -> ZoomChannels {
+        discover(start, &[(VIDEO_PORT, 1000), (AUDIO_PORT, 200), (CONTROL_PORT, 60)], 0, to_ms)
+    }
+
+    fn at(start: Instant, ms: u64) -> Instant {
+        start + std::time::Duration::from_millis(ms)
+    }
+
+    /// Update the state every 100ms from `from_ms` to `to_ms` inclusive, as the main loop does, returning the events
+    fn run_updates(state: &mut ZoomSessionState, channels: &ZoomChannels, start: Instant, from_ms: u64, to_ms: u64)
+                   -> Vec<SessionEvent> {
+        let mut events = Vec::new();
+        for ms in (from_ms..=to_ms).step_by(100) {
+            let previous = state.clone();
+            state.update_channels(channels, at(start, ms));
+            events.extend(state.events_since(&previous));
+        }
+        events
+    }
+
+    #[test]
+    fn debounce_off_holds_on_until_enough_off_updates() {
+        let mut off_updates = 0;
+        let (on, off) = (ZoomChannelStatus::On, ZoomChannelStatus::Off);
+
+        assert_eq!(debounce_off(on, off, &mut off_updates, 3), on);
+        assert_eq!(debounce_off(on, off, &mut off_updates, 3), on);
+        assert_eq!(debounce_off(on, off, &mut off_updates, 3), off);
+        assert_eq!(off_updates, 0);
+    }
+
+    #[test]
+    fn debounce_off_turns_back_on_straight_away() {
+        let mut off_updates = 0;
+        let (on, off) = (ZoomChannelStatus::On, ZoomChannelStatus::Off);
+
+        assert_eq!(debounce_off(on, off, &mut off_updates, 3), on);
+        assert_eq!(debounce_off(on, on, &mut off_updates, 3), on);
+        // A single blip doesn't count towards the next one
+        assert_eq!(debounce_off(on, off, &mut off_updates, 3), on);
+        assert_eq!(debounce_off(on, off, &mut off_updates, 3), on);
+        assert_eq!(debounce_off(off, on, &mut off_updates, 3), on);
+    }
+
+    #[test]
+    fn debounce_off_of_one_reports_off_straight_away() {
+        let mut off_updates = 0;
+        assert_eq!(debounce_off(ZoomChannelStatus::On, ZoomChannelStatus::Off, &mut off_updates, 1), ZoomChannelStatus::Off);
+        assert_eq!(debounce_off(ZoomChannelStatus::Unknown, ZoomChannelStatus::Off, &mut off_updates, 3), ZoomChannelStatus::Off);
+    }
+
+    #[test]
+    fn control_on_its_own_is_connecting() {
+        let start = Instant::now();
+        let mut state = ZoomSessionState::new();
+        let events = run_updates(&mut state, &discover(start, &[(CONTROL_PORT, 60)], 0, 1000), start, 900, 1000);
+
+        assert!(events.is_empty());
+        assert!(state.connecting);
+        assert_eq!(state.control, ZoomChannelStatus::On);
+        assert_eq!((state.audio, state.video), (ZoomChannelStatus::Unknown, ZoomChannelStatus::Unknown));
+        assert_ne!(state.call, ZoomChannelStatus::On);
+
+        // The call starts once the media does
+        let events = run_updates(&mut state, &full_call(start, 2000), start, 1900, 2000);
+        assert_eq!(events, vec![SessionEvent::CallStarted]);
+        assert!(!state.connecting);
+    }
+
+    #[test]
+    fn audio_only_call_is_ready_to_monitor_once_settled() {
+        let start = Instant::now();
+        let streams = [(AUDIO_PORT, 200), (CONTROL_PORT, 60)];
+        let mut state = ZoomSessionState::new();
+
+        // Video might still turn up for a while
+        run_updates(&mut state, &discover(start, &streams, 0, 3000), start, 2900, 3000);
+        assert!(state.is_in_meeting());
+        assert!(!state.ready_to_monitor(at(start, 3000)));
+
+        run_updates(&mut state, &discover(start, &streams, 0, 6000), start, 5900, 6000);
+        assert!(state.ready_to_monitor(at(start, 6000)));
+        assert_eq!((state.audio, state.video), (ZoomChannelStatus::On, ZoomChannelStatus::Unknown));
+
+        state.mode = CaptureMode::Monitor;
+        run_updates(&mut state, &discover(start, &streams, 0, 7000), start, 6900, 7000);
+        assert_eq!((state.audio, state.video), (ZoomChannelStatus::On, ZoomChannelStatus::Unknown));
+    }
+
+    #[test]
+    fn update_channels_finds_a_call_with_camera_and_mic_on() {
+        let start = Instant::now();
+        let mut state = ZoomSessionState::new();
+        let events = run_updates(&mut state, &full_call(start, 1000), start, 900, 1000);
+
+        assert_eq!(events, vec![SessionEvent::CallStarted]);
+        assert_eq!((state.call, state.video, state.audio, state.control),
+            (ZoomChannelStatus::On, ZoomChannelStatus::On, ZoomChannelStatus::On, ZoomChannelStatus::On));
+        assert!(state.camera_on() && state.mic_on() && !state.mic_muted() && !state.camera_off());
+        assert!(state.channels_settled(at(start, 1000)));
+    }
+
+    #[test]
+    fn audio_status_follows_the_audio_stream_not_the_video() {
+        let start = Instant::now();
+        // The camera went off a second ago, but the mic's still sending
+        let mut channels = discover(start, &[(AUDIO_PORT, 200), (CONTROL_PORT, 60)], 0, 2000);
+        channels.video = full_call(start, 1000).video;
+
+        let mut state = ZoomSessionState::new();
+        run_updates(&mut state, &channels, start, 1900, 2000);
+
+        assert_eq!(state.audio, ZoomChannelStatus::On);
+        assert_eq!(state.video, ZoomChannelStatus::Off);
+    }
+
+    #[test]
+    fn a_lone_packet_does_not_start_a_call() {
+        let start = Instant::now();
+        let channels = discover(start, &[(VIDEO_PORT, 1000), (AUDIO_PORT, 200), (CONTROL_PORT, 60)], 0, 20);
+        assert_eq!(channels.packets_processed, 3);
+
+        let mut state = ZoomSessionState::new();
+        let events = run_updates(&mut state, &channels, start, 100, 500);
+
+        // Not established, so there's nothing to say whether there's a call either way
+        assert!(events.is_empty());
+        assert_eq!(state.call, ZoomChannelStatus::Unknown);
+        assert!(!state.is_in_meeting());
+    }
+
+    #[test]
+    fn wall_clock_jumping_back_does_not_flip_the_channels() {
+        let start = Instant::now();
+        // Halfway through, the system clock is set back an hour, but packets keep arriving every 20ms
+        let packets = (0..100u64).flat_map(|i| [(VIDEO_PORT, 1000), (AUDIO_PORT, 200), (CONTROL_PORT, 60)].map(|(port, length)| {
+            let offset = std::time::Duration::from_millis(i * 20);
+            let clock_change = if i < 50 { Duration::zero() } else { Duration::hours(1) };
+            let time = PacketTime {
+                captured_at: Utc.timestamp_opt(1_600_000_000, 0).unwrap() + Duration::from_std(offset).unwrap() - clock_change,
+                seen_at: start + offset
+            };
+            NextPacket::Udp(port, length, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), time)
+        })).collect();
+        let channel_map = RwLock::new(ZoomChannels::new());
+        PortDiscoveryCapture::discover(&mut VecPacketSource::new(packets), &CaptureOptions::default(), &channel_map,
+            &SimpleAtomicBool::new(false)).unwrap();
+        let channels = channel_map.into_inner().unwrap();
+
+        let mut state = ZoomSessionState::new();
+        let events = run_updates(&mut state, &channels, start, 1900, 2000);
+
+        assert_eq!(events, vec![SessionEvent::CallStarted]);
+        assert_eq!((state.call, state.video, state.audio),
+            (ZoomChannelStatus::On, ZoomChannelStatus::On, ZoomChannelStatus::On));
+        assert_eq!(channels.reassignments, 0);
+    }
+
+    /// A call that runs to `to_ms`, with the video's 1000 byte packets sent every `video_every_ms`
+    fn call_with_video_every(start: Instant, video_every_ms: usize, to_ms: u64) -> ZoomChannels {
+        let mut channels = discover(start, &[(AUDIO_PORT, 200), (CONTROL_PORT, 60)], 0, to_ms);
+        channels.video = discover_every(start, &[(VIDEO_PORT, 1000)], 0, to_ms, video_every_ms).video;
+        channels
+    }
+
+    /// Update every 100ms from `from_ms` to `to_ms`, with the channels for each time, returning the video steps seen
+    fn video_steps(state: &mut ZoomSessionState, start: Instant, from_ms: u64, to_ms: u64,
+                   channels_at: impl Fn(u64) -> ZoomChannels) -> Vec<SessionEvent> {
+        let mut steps = Vec::new();
+        for ms in (from_ms..=to_ms).step_by(100) {
+            let previous = state.clone();
+            state.update_channels(&channels_at(ms), at(start, ms));
+            steps.extend(state.events_since(&previous).into_iter()
+                .filter(|event| matches!(event, SessionEvent::VideoBitrateChanged { .. })));
+        }
+        steps
+    }
+
+    #[test]
+    fn video_step_is_reported_once_it_sticks() {
+        let start = Instant::now();
+        let (slow, fast) = (call_with_video_every(start, 20, 8000), call_with_video_every(start, 10, 8000));
+        let kbps = |channels: &ZoomChannels| channels.video.and_then(|stream| stream.bitrate_kbps()).unwrap();
+        let mut state = ZoomSessionState::new();
+        state.video_step_percent = Some(40);
+
+        // The bitrate doubles at 2000ms, and is reported once it's stayed there for the 3000ms confirm time
+        let steps = video_steps(&mut state, start, 1000, 4900, |ms| if ms < 2000 { slow.clone() } else { fast.clone() });
+        assert!(steps.is_empty());
+        let steps = video_steps(&mut state, start, 5000, 8000, |_| fast.clone());
+        assert_eq!(steps, vec![SessionEvent::VideoBitrateChanged { from_kbps: kbps(&slow), to_kbps: kbps(&fast) }]);
+    }
+
+    #[test]
+    fn video_step_ignores_noisy_bitrate() {
+        let start = Instant::now();
+        let (slow, fast) = (call_with_video_every(start, 20, 8000), call_with_video_every(start, 10, 8000));
+        let mut state = ZoomSessionState::new();
+        state.video_step_percent = Some(40);
+
+        // Jumping up and down every second never stays moved for long enough
+        let steps = video_steps(&mut state, start, 1000, 8000, |ms| if ms / 1000 % 2 == 0 { slow.clone() } else { fast.clone() });
+        assert!(steps.is_empty());
+    }
+
+    /// Regression test for the call being reported as going on for ever after every packet stops
+    #[test]
+    fn call_ends_once_every_channel_stops() {
+        let start = Instant::now();
+        let channels = full_call(start, 1000);
+        let mut state = ZoomSessionState::new();
+
+        let events = run_updates(&mut state, &channels, start, 900, 10_000);
+
+        // The mic goes off while the call is still lingering, so a mute can be reported in between
+        assert_eq!(events.first(), Some(&SessionEvent::CallStarted));
+        assert_eq!(events.last(), Some(&SessionEvent::CallEnded));
+        assert_eq!(state.call, ZoomChannelStatus::Off);
+        assert_eq!(state.video, ZoomChannelStatus::Off);
+        assert_eq!(state.audio, ZoomChannelStatus::Off);
+        assert_eq!(state.control, ZoomChannelStatus::Off);
+        assert!(!state.is_in_meeting() && !state.mic_muted() && !state.camera_off());
+    }
+
+    #[test]
+    fn camera_is_live_only_above_the_bitrate_threshold() {
+        let start = Instant::now();
+        let channels = full_call(start, 1000);
+        let kbps = channels.video.and_then(|stream| stream.bitrate_kbps()).unwrap();
+
+        let mut state = ZoomSessionState::new();
+        state.camera_live_above_kbps = kbps - 1;
+        run_updates(&mut state, &channels, start, 900, 1000);
+        assert!(state.camera_on() && state.camera_live);
+
+        let mut state = ZoomSessionState::new();
+        state.camera_live_above_kbps = kbps;
+        run_updates(&mut state, &channels, start, 900, 1000);
+        assert!(state.camera_on() && !state.camera_live);
+    }
+
+    #[test]
+    fn placeholder_video_is_not_live() {
+        let start = Instant::now();
+        // Video sized packets, but only ten a second
+        let mut channels = discover_every(start, &[(VIDEO_PORT, 1000)], 0, 2000, 100);
+        let audio = discover(start, &[(AUDIO_PORT, 200), (CONTROL_PORT, 60)], 0, 2000);
+        channels.audio = audio.audio;
+        channels.control = audio.control;
+
+        let mut state = ZoomSessionState::new();
+        run_updates(&mut state, &channels, start, 1900, 2000);
+        assert!(state.camera_on() && !state.camera_live);
+    }
+
+    #[test]
+    fn video_stays_on_until_enough_updates_look_off() {
+        let start = Instant::now();
+        let channels = full_call(start, 1000);
+        let mut state = ZoomSessionState::new();
+        state.off_debounce = 4;
+        run_updates(&mut state, &channels, start, 900, 1000);
+        assert_eq!(state.video, ZoomChannelStatus::On);
+
+        // The last packets were at 980ms, so the video looks off from 1200ms, but holds on for three updates
+        run_updates(&mut state, &channels, start, 1100, 1400);
+        assert_eq!((state.video_raw, state.video), (ZoomChannelStatus::Off, ZoomChannelStatus::On));
+        assert_eq!((state.audio_raw, state.audio), (ZoomChannelStatus::Off, ZoomChannelStatus::On));
+
+        run_updates(&mut state, &channels, start, 1500, 1500);
+        assert_eq!((state.video_raw, state.video), (ZoomChannelStatus::Off, ZoomChannelStatus::Off));
+        assert_eq!(state.audio, ZoomChannelStatus::Off);
+    }
+
+    #[test]
+    fn call_lingers_past_the_timeout() {
+        let start = Instant::now();
+        let channels = full_call(start, 1000);
+        let mut state = ZoomSessionState::new();
+        // The last packets were at 980ms, so the 3000ms call timeout runs out just before 4000ms
+        run_updates(&mut state, &channels, start, 900, 4000);
+        assert_eq!(state.call_raw, ZoomChannelStatus::Off);
+        assert_eq!(state.call, ZoomChannelStatus::On);
+
+        run_updates(&mut state, &channels, start, 4100, 4900);
+        assert_eq!(state.call, ZoomChannelStatus::On);
+        run_updates(&mut state, &channels, start, 5000, 5000);
+        assert_eq!(state.call, ZoomChannelStatus::Off);
+    }
+
+    #[test]
+    fn call_linger_resets_if_packets_come_back() {
+        let start = Instant::now();
+        let mut state = ZoomSessionState::new();
+        run_updates(&mut state, &full_call(start, 1000), start, 900, 4500);
+        assert_eq!((state.call_raw, state.call), (ZoomChannelStatus::Off, ZoomChannelStatus::On));
+
+        // The control channel carries on after a blip, so the call never ends
+        let mut channels = discover(start, &[(CONTROL_PORT, 60)], 4500, 5000);
+        channels.video = state.channels.video;
+        channels.audio = state.channels.audio;
+        let events = run_updates(&mut state, &channels, start, 4600, 6000);
+        assert!(events.is_empty());
+        assert_eq!((state.call_raw, state.call), (ZoomChannelStatus::On, ZoomChannelStatus::On));
+    }
+
+    #[test]
+    fn call_end_grace_holds_the_call_through_a_teardown_tail() {
+        let start = Instant::now();
+        let mut channels = full_call(start, 1000);
+        // A few control sized packets on a fresh port once the media's stopped, too few to establish a stream
+        let teardown = discover(start, &[(CONTROL_PORT + 1, 60)], 3500, 3560);
+        channels.streams.extend(teardown.streams);
+
+        // Without a grace the call ends when the linger runs out, teardown or not
+        let mut state = ZoomSessionState::new();
+        run_updates(&mut state, &channels, start, 900, 5000);
+        assert_eq!(state.call, ZoomChannelStatus::Off);
+
+        // With one, it holds on until the grace has passed since the last teardown packet at 3540ms
+        let mut state = ZoomSessionState::new();
+        state.call_end_grace_ms = 2000;
+        run_updates(&mut state, &channels, start, 900, 5500);
+        assert_eq!((state.call_raw, state.call), (ZoomChannelStatus::Off, ZoomChannelStatus::On));
+        let events = run_updates(&mut state, &channels, start, 5600, 5600);
+        assert_eq!(events, vec![SessionEvent::CallEnded]);
+    }
+
+    #[test]
+    fn session_summary_adds_up_a_scripted_call() {
+        let state = |call, video, audio| {
+            let mut state = ZoomSessionState::new();
+            state.call = call;
+            state.video = video;
+            state.audio = audio;
+            state
+        };
+        let (on, off) = (ZoomChannelStatus::On, ZoomChannelStatus::Off);
+
+        // Each state, how long it lasted, and the events that led into it
+        let script = [
+            (state(off, off, off), 5, vec![]),
+            (state(on, off, off), 10, vec![SessionEvent::CallStarted]),
+            (state(on, off, on), 20, vec![SessionEvent::MicUnmuted]),
+            (state(on, on, off), 30, vec![SessionEvent::MicMuted]),
+            (state(off, off, off), 5, vec![SessionEvent::CallEnded])
+        ];
+
+        let mut summary = SessionSummary::new();
+        for (i, (previous, seconds, _)) in script.iter().enumerate() {
+            let events = script.get(i + 1).map_or(&[][..], |(_, _, events)| &events[..]);
+            summary.record(previous, Duration::seconds(*seconds), events);
+        }
+
+        assert_eq!(summary.calls, 1);
+        assert_eq!(summary.call_time, Duration::seconds(60));
+        assert_eq!(summary.mic_time, Duration::seconds(20));
+        assert_eq!(summary.camera_time, Duration::seconds(30));
+    }
+
+    #[test]
+    fn forget_idle_streams_keeps_only_the_current_call() {
+        let start = Instant::now();
+        let mut channels = full_call(start, 1000);
+        let next_call = discover(start, &[(60000, 1000), (60001, 200)], 20_000, 21_000);
+        channels.streams.extend(next_call.streams);
+
+        channels.forget_idle_streams(at(start, 21_000), Duration::milliseconds(3000));
+
+        let ports: Vec<u16> = channels.streams.values().map(|stream| stream.source_port()).collect();
+        assert_eq!(ports, vec![60000, 60001]);
+        // The old call's channels are still there to report on
+        assert_eq!(channels.video.map(|stream| stream.source_port()), Some(VIDEO_PORT));
+
+        channels.forget_idle_streams(at(start, 30_000), Duration::milliseconds(3000));
+        assert!(stream_analyser::monitored_streams(&channels).is_empty());
+    }
+
+    #[test]
+    fn events_since_reports_mic_mutes_only_during_a_call() {
+        let mut previous = ZoomSessionState::new();
+        let mut state = ZoomSessionState::new();
+        state.call = ZoomChannelStatus::On;
+        state.audio = ZoomChannelStatus::On;
+        // The mic starting with the call isn't an unmute
+        assert_eq!(state.events_since(&previous), vec![SessionEvent::CallStarted]);
+
+        previous = state.clone();
+        state.audio = ZoomChannelStatus::Off;
+        assert_eq!(state.events_since(&previous), vec![SessionEvent::MicMuted]);
+        assert!(state.mic_muted());
+
+        previous = state.clone();
+        state.audio = ZoomChannelStatus::On;
+        assert_eq!(state.events_since(&previous), vec![SessionEvent::MicUnmuted]);
+
+        previous = state.clone();
+        state.call = ZoomChannelStatus::Off;
+        state.audio = ZoomChannelStatus::Off;
+        assert_eq!(state.events_since(&previous), vec![SessionEvent::CallEnded]);
+        assert_eq!(state.events_since(&state.clone()), vec![]);
+    }
+
+    #[test]
+    fn quality_bands_parse_highest_first() {
+        assert_eq!(QualityBand::parse_list("0:low,1200:hd, 400:sd"), Ok(QualityBand::defaults()));
+        assert!(QualityBand::parse_list("1200:4k").is_err());
+        assert!(QualityBand::parse_list("fast:hd").is_err());
+        assert!(QualityBand::parse_list("1200").is_err());
+    }
+
+    #[test]
+    fn quality_bands_split_on_their_thresholds() {
+        let bands = QualityBand::defaults();
+        let quality_for = |kbps| QualityBand::quality_for(&bands, kbps);
+
+        assert_eq!(quality_for(0), Some(VideoQuality::Low));
+        assert_eq!(quality_for(399), Some(VideoQuality::Low));
+        assert_eq!(quality_for(400), Some(VideoQuality::Low));
+        assert_eq!(quality_for(401), Some(VideoQuality::Sd));
+        assert_eq!(quality_for(1199), Some(VideoQuality::Sd));
+        assert_eq!(quality_for(1200), Some(VideoQuality::Sd));
+        assert_eq!(quality_for(1201), Some(VideoQuality::Hd));
+
+        // Without a band starting at 0, there's nothing for slow video to fall into
+        let bands = QualityBand::parse_list("1200:hd,400:sd").unwrap();
+        assert_eq!(QualityBand::quality_for(&bands, 400), None);
+        assert_eq!(QualityBand::quality_for(&bands, 401), Some(VideoQuality::Sd));
+    }
+
+    #[test]
+    fn activity_levels_interpolate_between_points() {
+        let points = ActivityPoint::defaults();
+        assert_eq!(ActivityPoint::level_for(&points, 0), 0);
+        assert_eq!(ActivityPoint::level_for(&points, 20), 15);
+        assert_eq!(ActivityPoint::level_for(&points, 1000), 80);
+        assert_eq!(ActivityPoint::level_for(&points, 10_000), 100);
+        assert!(ActivityPoint::parse_list("100:101").is_err());
+    }
 }